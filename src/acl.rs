@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{info, warn};
+use parking_lot::RwLock;
+use pektin_common::deadpool_redis::redis::AsyncCommands;
+use pektin_common::deadpool_redis::Pool;
+use pektin_common::proto::rr::Name;
+use serde::Deserialize;
+
+use crate::clients::ClientMatcher;
+use crate::{PektinError, PektinResult, Transport};
+
+/// The db key whose ACLs replace the ones from the ACL file, so that they can be managed by the
+/// UI. It has no `:` and therefore can't clash with the key of an RRset.
+const ACL_KEY: &str = "ACL";
+
+#[derive(Deserialize)]
+struct AclConfig {
+    #[serde(default)]
+    listeners: HashMap<String, Vec<RuleConfig>>,
+    #[serde(default)]
+    zones: HashMap<String, Vec<RuleConfig>>,
+}
+
+#[derive(Deserialize)]
+struct RuleConfig {
+    action: AclAction,
+    #[serde(default)]
+    networks: Vec<String>,
+    #[serde(default)]
+    tsig_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone)]
+struct Rule {
+    action: AclAction,
+    clients: ClientMatcher,
+}
+
+impl Rule {
+    /// A rule without networks and TSIG keys matches every client.
+    fn matches(&self, client_ip: Option<IpAddr>, tsig_key: Option<&Name>) -> bool {
+        self.clients.is_empty() || self.clients.matches(client_ip, tsig_key)
+    }
+}
+
+/// Returns the action of the first matching rule.
+fn evaluate(
+    rules: &[Rule],
+    client_ip: Option<IpAddr>,
+    tsig_key: Option<&Name>,
+) -> Option<AclAction> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(client_ip, tsig_key))
+        .map(|rule| rule.action)
+}
+
+#[derive(Clone, Default)]
+struct AclSet {
+    listeners: HashMap<Transport, Vec<Rule>>,
+    /// Sorted with the zones with the most labels first.
+    zones: Vec<(Name, Vec<Rule>)>,
+}
+
+impl AclSet {
+    fn parse(json: &str) -> PektinResult<Self> {
+        let acl_config: AclConfig = serde_json::from_str(json)?;
+        let invalid = |msg: String| PektinError::InvalidConfig(msg);
+        let parse_rules = |rules: Vec<RuleConfig>| {
+            rules
+                .into_iter()
+                .map(|rule| {
+                    Ok(Rule {
+                        action: rule.action,
+                        clients: ClientMatcher::parse(&rule.networks, &rule.tsig_keys, "ACL")?,
+                    })
+                })
+                .collect::<PektinResult<Vec<_>>>()
+        };
+
+        let listeners = acl_config
+            .listeners
+            .into_iter()
+            .map(|(listener, rules)| {
+                let transport = match listener.as_str() {
+                    "udp" => Transport::Udp,
+                    "tcp" => Transport::Tcp,
+                    "https" => Transport::Https,
+                    _ => return Err(invalid(format!("unknown listener {} in ACL", listener))),
+                };
+                Ok((transport, parse_rules(rules)?))
+            })
+            .collect::<PektinResult<_>>()?;
+
+        let mut zones = acl_config
+            .zones
+            .into_iter()
+            .map(|(zone, rules)| {
+                let zone = Name::from_utf8(&zone)
+                    .map_err(|_| invalid(format!("invalid zone {} in ACL", zone)))?;
+                Ok((zone, parse_rules(rules)?))
+            })
+            .collect::<PektinResult<Vec<_>>>()?;
+        // the - makes it sort the zones with the most labels first
+        zones.sort_by_key(|(zone, _)| -(zone.num_labels() as i16));
+
+        Ok(Self { listeners, zones })
+    }
+}
+
+/// Access control lists that restrict which clients may query the server over which listener
+/// and which zones they may query.
+///
+/// The rules of an ACL are checked in order and the first one matching the client decides.
+/// Clients that match no rule are allowed.
+#[derive(Default)]
+pub struct Acls {
+    /// The ACLs from the ACL file, used if there are none in the db.
+    configured: AclSet,
+    active: RwLock<AclSet>,
+}
+
+impl Acls {
+    /// Loads the ACLs from a JSON file of the following form.
+    ///
+    /// ```json
+    /// {
+    ///     "listeners": {
+    ///         "tcp": [{ "action": "allow", "networks": ["10.0.0.0/8"] }, { "action": "deny" }]
+    ///     },
+    ///     "zones": {
+    ///         "internal.example.com.": [
+    ///             { "action": "allow", "networks": ["10.0.0.0/8"], "tsig_keys": ["ops."] },
+    ///             { "action": "deny" }
+    ///         ]
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// The listeners are `udp`, `tcp` and `https`. The ACL of a zone applies to all names in it
+    /// that aren't in a more specific zone with its own ACL.
+    pub fn from_file(path: impl AsRef<Path>) -> PektinResult<Self> {
+        let configured = AclSet::parse(&std::fs::read_to_string(path)?)?;
+        Ok(Self {
+            active: RwLock::new(configured.clone()),
+            configured,
+        })
+    }
+
+    /// Returns whether the client may query the given name over the given transport, given its
+    /// address and the name of the (verified) TSIG key its request was signed with.
+    pub fn allows(
+        &self,
+        transport: Transport,
+        client_ip: Option<IpAddr>,
+        tsig_key: Option<&Name>,
+        query_name: Option<&Name>,
+    ) -> bool {
+        let acls = self.active.read();
+        let listener_action = acls
+            .listeners
+            .get(&transport)
+            .and_then(|rules| evaluate(rules, client_ip, tsig_key));
+        let zone_action = query_name
+            .and_then(|name| acls.zones.iter().find(|(zone, _)| zone.zone_of(name)))
+            .and_then(|(_, rules)| evaluate(rules, client_ip, tsig_key));
+        listener_action != Some(AclAction::Deny) && zone_action != Some(AclAction::Deny)
+    }
+
+    /// Periodically loads the ACLs stored in the db, which replace the ones from the ACL file
+    /// while they exist.
+    pub async fn follow(&self, db_pool: &Pool, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        let mut overridden = false;
+        loop {
+            ticker.tick().await;
+            match load_acls(db_pool).await {
+                Ok(db_acls) => self.apply_db_acls(db_acls, &mut overridden),
+                Err(e) => warn!("Could not load ACLs from db: {}", e),
+            }
+        }
+    }
+
+    /// Activates the ACLs from the db, or the ones from the ACL file if there are none in the db.
+    ///
+    /// `overridden` tracks whether the ACLs from the db are active.
+    fn apply_db_acls(&self, db_acls: Option<AclSet>, overridden: &mut bool) {
+        match db_acls {
+            Some(acls) => {
+                if !*overridden {
+                    info!("Using the ACLs from the db");
+                    *overridden = true;
+                }
+                *self.active.write() = acls;
+            }
+            None => {
+                if *overridden {
+                    info!("Using the ACLs from the ACL file");
+                    *overridden = false;
+                    *self.active.write() = self.configured.clone();
+                }
+            }
+        }
+    }
+}
+
+async fn load_acls(db_pool: &Pool) -> anyhow::Result<Option<AclSet>> {
+    let mut con = db_pool
+        .get()
+        .await
+        .context("Could not get db connection from pool")?;
+    let json: Option<String> = con.get(ACL_KEY).await?;
+    json.map(|json| AclSet::parse(&json).context("Invalid ACLs"))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_acls(json: &str) -> Acls {
+        let configured = AclSet::parse(json).unwrap();
+        Acls {
+            active: RwLock::new(configured.clone()),
+            configured,
+        }
+    }
+
+    fn allows(
+        acls: &Acls,
+        transport: Transport,
+        client_ip: &str,
+        tsig_key: Option<&str>,
+        query_name: &str,
+    ) -> bool {
+        let tsig_key = tsig_key.map(|key| Name::from_ascii(key).unwrap());
+        acls.allows(
+            transport,
+            Some(client_ip.parse().unwrap()),
+            tsig_key.as_ref(),
+            Some(&Name::from_ascii(query_name).unwrap()),
+        )
+    }
+
+    #[test]
+    fn the_first_matching_rule_decides() {
+        let acls = parse_acls(
+            r#"{ "listeners": { "tcp": [
+                { "action": "deny", "networks": ["10.1.0.0/16"] },
+                { "action": "allow", "networks": ["10.0.0.0/8"], "tsig_keys": ["ops."] },
+                { "action": "deny" }
+            ] } }"#,
+        );
+        let tcp = Transport::Tcp;
+        assert!(!allows(&acls, tcp, "10.1.2.3", None, "example.com."));
+        assert!(!allows(
+            &acls,
+            tcp,
+            "10.1.2.3",
+            Some("ops."),
+            "example.com."
+        ));
+        assert!(allows(&acls, tcp, "10.2.3.4", None, "example.com."));
+        assert!(allows(
+            &acls,
+            tcp,
+            "192.0.2.1",
+            Some("ops."),
+            "example.com."
+        ));
+        // the empty rule matches everyone else
+        assert!(!allows(&acls, tcp, "192.0.2.1", None, "example.com."));
+        assert!(!allows(
+            &acls,
+            tcp,
+            "2001:db8::1",
+            Some("other."),
+            "example.com."
+        ));
+        // listeners without ACL allow everyone
+        assert!(allows(
+            &acls,
+            Transport::Udp,
+            "192.0.2.1",
+            None,
+            "example.com."
+        ));
+        // clients that match no rule are allowed
+        let udp_acls = parse_acls(
+            r#"{ "listeners": { "udp": [{ "action": "deny", "networks": ["10.0.0.0/8"] }] } }"#,
+        );
+        assert!(allows(
+            &udp_acls,
+            Transport::Udp,
+            "192.0.2.1",
+            None,
+            "example.com."
+        ));
+    }
+
+    #[test]
+    fn the_most_specific_zone_decides() {
+        let acls = parse_acls(
+            r#"{ "zones": {
+                "example.com.": [{ "action": "deny", "networks": ["192.0.2.0/24"] }],
+                "internal.example.com.": [
+                    { "action": "allow", "networks": ["192.0.2.0/24"] },
+                    { "action": "deny" }
+                ]
+            } }"#,
+        );
+        let udp = Transport::Udp;
+        assert!(!allows(&acls, udp, "192.0.2.1", None, "www.example.com."));
+        assert!(allows(&acls, udp, "198.51.100.1", None, "www.example.com."));
+        assert!(allows(
+            &acls,
+            udp,
+            "192.0.2.1",
+            None,
+            "db.internal.example.com."
+        ));
+        assert!(allows(
+            &acls,
+            udp,
+            "192.0.2.1",
+            None,
+            "Internal.Example.com."
+        ));
+        assert!(!allows(
+            &acls,
+            udp,
+            "198.51.100.1",
+            None,
+            "db.internal.example.com."
+        ));
+        // names outside of all zones with ACLs
+        assert!(allows(&acls, udp, "192.0.2.1", None, "example.org."));
+        assert!(allows(&acls, udp, "192.0.2.1", None, "com."));
+    }
+
+    #[test]
+    fn listener_and_zone_acls_must_both_allow() {
+        let acls = parse_acls(
+            r#"{
+                "listeners": { "https": [
+                    { "action": "deny", "networks": ["198.51.100.0/24"] },
+                    { "action": "allow" }
+                ] },
+                "zones": { "internal.": [
+                    { "action": "allow", "tsig_keys": ["ops."] },
+                    { "action": "deny" }
+                ] }
+            }"#,
+        );
+        let https = Transport::Https;
+        assert!(allows(
+            &acls,
+            https,
+            "192.0.2.1",
+            Some("ops."),
+            "db.internal."
+        ));
+        // the listener denies
+        assert!(!allows(
+            &acls,
+            https,
+            "198.51.100.1",
+            Some("ops."),
+            "db.internal."
+        ));
+        // the zone denies, although the listener allows
+        assert!(!allows(&acls, https, "192.0.2.1", None, "db.internal."));
+        // both deny
+        assert!(!allows(&acls, https, "198.51.100.1", None, "db.internal."));
+        // requests without question only go through the listener ACL
+        assert!(acls.allows(https, Some("192.0.2.1".parse().unwrap()), None, None));
+        assert!(!acls.allows(https, Some("198.51.100.1".parse().unwrap()), None, None));
+    }
+
+    #[test]
+    fn db_acls_replace_the_configured_ones_while_they_exist() {
+        let acls = parse_acls(r#"{ "listeners": { "udp": [{ "action": "deny" }] } }"#);
+        let udp = Transport::Udp;
+        let mut overridden = false;
+
+        acls.apply_db_acls(None, &mut overridden);
+        assert!(!overridden);
+        assert!(!allows(&acls, udp, "192.0.2.1", None, "example.com."));
+
+        let db_acls = AclSet::parse(
+            r#"{ "zones": { "example.com.": [{ "action": "deny", "networks": ["10.0.0.0/8"] }] } }"#,
+        )
+        .unwrap();
+        acls.apply_db_acls(Some(db_acls), &mut overridden);
+        assert!(overridden);
+        assert!(allows(&acls, udp, "192.0.2.1", None, "example.com."));
+        assert!(!allows(&acls, udp, "10.0.0.1", None, "example.com."));
+
+        // the db ACLs are deleted
+        acls.apply_db_acls(None, &mut overridden);
+        assert!(!overridden);
+        assert!(!allows(&acls, udp, "192.0.2.1", None, "example.com."));
+        assert!(!allows(&acls, udp, "10.0.0.1", None, "example.org."));
+    }
+}
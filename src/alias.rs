@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use pektin_common::proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use pektin_common::proto::rr::{Name, RData, RecordType};
+use serde::Deserialize;
+use tokio::time::timeout;
+
+use crate::{listeners, PektinError, PektinResult};
+
+/// How long to wait for a response from the upstream resolver.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to cache that the upstream resolver has no records for a target.
+const NEGATIVE_CACHE_SECONDS: u32 = 30;
+
+/// An ALIAS pseudo-record, stored as JSON at `<name>:ALIAS`.
+///
+/// ```json
+/// { "target": "example.cdn.net.", "ttl": 300 }
+/// ```
+///
+/// A and AAAA queries for the name are answered with the A and AAAA records of the target, which
+/// makes it possible to point the zone apex at a hostname. The TTL of the answers is at most
+/// `ttl`. Since the answers are synthesized at query time, they are not signed.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Alias {
+    pub target: String,
+    pub ttl: u32,
+}
+
+struct CachedAnswer {
+    records: Vec<(RData, u32)>,
+    expires: Instant,
+}
+
+/// Resolves ALIAS targets outside of our zones using an upstream resolver and caches the results.
+#[derive(Default)]
+pub struct AliasResolver {
+    upstream: Option<SocketAddr>,
+    cache: Mutex<HashMap<(Name, RecordType), CachedAnswer>>,
+}
+
+impl AliasResolver {
+    /// If `upstream` is `None`, only ALIAS targets in our zones can be resolved.
+    pub fn new(upstream: Option<SocketAddr>) -> Self {
+        Self {
+            upstream,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the data and TTL of the records of the given type at the target.
+    ///
+    /// Returns no records if there is no upstream resolver.
+    pub async fn resolve(
+        &self,
+        target: &Name,
+        rr_type: RecordType,
+    ) -> PektinResult<Vec<(RData, u32)>> {
+        let upstream = match self.upstream {
+            Some(u) => u,
+            None => return Ok(vec![]),
+        };
+        let cache_key = (target.to_lowercase(), rr_type);
+
+        if let Some(cached) = self.cache.lock().get(&cache_key) {
+            let remaining = cached.expires.saturating_duration_since(Instant::now());
+            if !remaining.is_zero() {
+                let remaining = remaining.as_secs() as u32;
+                return Ok(cached
+                    .records
+                    .iter()
+                    .map(|(data, ttl)| (data.clone(), (*ttl).min(remaining)))
+                    .collect());
+            }
+        }
+
+        let records = query_upstream(upstream, target, rr_type).await?;
+        let ttl = records
+            .iter()
+            .map(|(_, ttl)| *ttl)
+            .min()
+            .unwrap_or(NEGATIVE_CACHE_SECONDS);
+        let mut cache = self.cache.lock();
+        cache.retain(|_, cached| cached.expires > Instant::now());
+        cache.insert(
+            cache_key,
+            CachedAnswer {
+                records: records.clone(),
+                expires: Instant::now() + Duration::from_secs(ttl as u64),
+            },
+        );
+        Ok(records)
+    }
+}
+
+async fn query_upstream(
+    upstream: SocketAddr,
+    target: &Name,
+    rr_type: RecordType,
+) -> PektinResult<Vec<(RData, u32)>> {
+    let mut query = Message::new();
+    query.set_id(rand::random());
+    query.set_message_type(MessageType::Query);
+    query.set_op_code(OpCode::Query);
+    query.set_recursion_desired(true);
+    query.add_query(Query::query(target.clone(), rr_type));
+
+    let socket = listeners::connect_udp(upstream).await?;
+    socket.send(&query.to_vec()?).await?;
+
+    let mut buf = vec![0; 4096];
+    let response = loop {
+        let len = timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf))
+            .await
+            .map_err(|_| PektinError::UpstreamError("timeout"))??;
+        let response = Message::from_vec(&buf[..len])?;
+        // ignore stray responses
+        if response.id() == query.id() {
+            break response;
+        }
+    };
+    match response.response_code() {
+        ResponseCode::NoError | ResponseCode::NXDomain => {}
+        _ => return Err(PektinError::UpstreamError("error response")),
+    }
+
+    // the answer may contain a CNAME chain, we only need the records at its end
+    Ok(response
+        .answers()
+        .iter()
+        .filter(|record| record.rr_type() == rr_type)
+        .filter_map(|record| record.data().map(|data| (data.clone(), record.ttl())))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use pektin_common::proto::rr::Record;
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    /// Starts an upstream resolver that sends the responses `respond` returns for each query and
+    /// counts the queries.
+    async fn stub_upstream(
+        respond: fn(&Message) -> Vec<Message>,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let query = Message::from_vec(&buf[..len]).unwrap();
+                for response in respond(&query) {
+                    socket
+                        .send_to(&response.to_vec().unwrap(), peer)
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+        (addr, queries)
+    }
+
+    fn response(query: &Message, answers: Vec<Record>) -> Message {
+        let mut response = Message::new();
+        response
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .add_queries(query.queries().to_vec())
+            .add_answers(answers);
+        response
+    }
+
+    fn a_record(name: &str, ttl: u32, addr: [u8; 4]) -> Record {
+        Record::from_rdata(
+            Name::from_ascii(name).unwrap(),
+            ttl,
+            RData::A(Ipv4Addr::from(addr)),
+        )
+    }
+
+    fn target() -> Name {
+        Name::from_ascii("example.cdn.net.").unwrap()
+    }
+
+    #[tokio::test]
+    async fn resolves_and_caches_targets() {
+        let (upstream, queries) = stub_upstream(|query| {
+            vec![response(
+                query,
+                vec![
+                    // the records at the end of a CNAME chain
+                    Record::from_rdata(
+                        Name::from_ascii("example.cdn.net.").unwrap(),
+                        600,
+                        RData::CNAME(Name::from_ascii("edge.cdn.net.").unwrap()),
+                    ),
+                    a_record("edge.cdn.net.", 300, [192, 0, 2, 1]),
+                    a_record("edge.cdn.net.", 60, [192, 0, 2, 2]),
+                ],
+            )]
+        })
+        .await;
+        let resolver = AliasResolver::new(Some(upstream));
+
+        let records = resolver.resolve(&target(), RecordType::A).await.unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (RData::A(Ipv4Addr::new(192, 0, 2, 1)), 300),
+                (RData::A(Ipv4Addr::new(192, 0, 2, 2)), 60)
+            ]
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        // cached until the lowest TTL expires, with the TTLs capped to the remaining time
+        let target = Name::from_ascii("Example.CDN.net.").unwrap();
+        let records = resolver.resolve(&target, RecordType::A).await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|(_, ttl)| (59..=60).contains(ttl)));
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        // other types are cached separately
+        resolver.resolve(&target, RecordType::AAAA).await.unwrap();
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn caches_missing_records() {
+        let (upstream, queries) = stub_upstream(|query| {
+            let mut response = response(query, vec![]);
+            response.set_response_code(ResponseCode::NXDomain);
+            vec![response]
+        })
+        .await;
+        let resolver = AliasResolver::new(Some(upstream));
+
+        assert_eq!(
+            resolver.resolve(&target(), RecordType::A).await.unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            resolver.resolve(&target(), RecordType::A).await.unwrap(),
+            vec![]
+        );
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        let cached = resolver.cache.lock();
+        let expires = cached[&(target(), RecordType::A)].expires;
+        let remaining = expires.saturating_duration_since(Instant::now());
+        assert!(remaining <= Duration::from_secs(NEGATIVE_CACHE_SECONDS as u64));
+        assert!(remaining > Duration::from_secs(NEGATIVE_CACHE_SECONDS as u64 - 5));
+    }
+
+    #[tokio::test]
+    async fn ignores_stray_responses() {
+        let (upstream, _) = stub_upstream(|query| {
+            let mut stray = response(query, vec![a_record("example.cdn.net.", 300, [6, 6, 6, 6])]);
+            stray.set_id(query.id().wrapping_add(1));
+            let answer = response(
+                query,
+                vec![a_record("example.cdn.net.", 300, [192, 0, 2, 1])],
+            );
+            vec![stray, answer]
+        })
+        .await;
+        let resolver = AliasResolver::new(Some(upstream));
+
+        assert_eq!(
+            resolver.resolve(&target(), RecordType::A).await.unwrap(),
+            vec![(RData::A(Ipv4Addr::new(192, 0, 2, 1)), 300)]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_on_error_responses() {
+        let (upstream, queries) = stub_upstream(|query| {
+            let mut response = response(query, vec![]);
+            response.set_response_code(ResponseCode::ServFail);
+            vec![response]
+        })
+        .await;
+        let resolver = AliasResolver::new(Some(upstream));
+
+        assert!(resolver.resolve(&target(), RecordType::A).await.is_err());
+        // errors aren't cached
+        assert!(resolver.resolve(&target(), RecordType::A).await.is_err());
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn resolves_nothing_without_upstream() {
+        let resolver = AliasResolver::new(None);
+        assert_eq!(
+            resolver.resolve(&target(), RecordType::A).await.unwrap(),
+            vec![]
+        );
+    }
+}
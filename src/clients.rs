@@ -0,0 +1,57 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use pektin_common::proto::rr::Name;
+
+use crate::{canonical_ip, PektinError, PektinResult};
+
+/// A set of clients, identified by the networks their addresses are in and the TSIG keys they
+/// sign their requests with, as used by views and ACLs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientMatcher {
+    networks: Vec<IpNet>,
+    tsig_keys: Vec<Name>,
+}
+
+impl ClientMatcher {
+    /// Parses the networks in CIDR notation and the TSIG key names.
+    ///
+    /// `context` describes where they come from in error messages, e.g. `view`.
+    pub fn parse(networks: &[String], tsig_keys: &[String], context: &str) -> PektinResult<Self> {
+        let invalid = |msg: String| PektinError::InvalidConfig(msg);
+        let networks = networks
+            .iter()
+            .map(|net| {
+                net.parse::<IpNet>()
+                    .map(|net| net.trunc())
+                    .map_err(|_| invalid(format!("invalid network {} in {}", net, context)))
+            })
+            .collect::<PektinResult<_>>()?;
+        let tsig_keys = tsig_keys
+            .iter()
+            .map(|key| {
+                Name::from_utf8(key)
+                    .map_err(|_| invalid(format!("invalid TSIG key name {} in {}", key, context)))
+            })
+            .collect::<PektinResult<_>>()?;
+        Ok(Self {
+            networks,
+            tsig_keys,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty() && self.tsig_keys.is_empty()
+    }
+
+    /// Returns whether the client's address is in one of the networks or its request was signed
+    /// with one of the TSIG keys.
+    pub fn matches(&self, client_ip: Option<IpAddr>, tsig_key: Option<&Name>) -> bool {
+        let ip_matches = client_ip
+            .map(canonical_ip)
+            .is_some_and(|ip| self.networks.iter().any(|net| net.contains(&ip)));
+        // names are compared case-insensitively
+        let key_matches = tsig_key.is_some_and(|key| self.tsig_keys.contains(key));
+        ip_matches || key_matches
+    }
+}
@@ -0,0 +1,387 @@
+use std::net::{IpAddr, SocketAddr};
+
+use ipnet::IpNet;
+use pektin_common::deadpool_redis::{self, Pool};
+use pektin_common::load_env;
+
+use crate::PektinResult;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub listen_addresses: Vec<SocketAddr>,
+    pub db_hostname: String,
+    pub db_username: String,
+    pub db_password: String,
+    pub db_port: u16,
+    pub db_retry_seconds: u64,
+    pub udp_sockets: usize,
+    pub udp_batch_size: usize,
+    pub tcp_timeout_seconds: u64,
+    pub tcp_max_in_flight: usize,
+    pub tcp_max_connections: usize,
+    pub tcp_max_connections_per_client: usize,
+    pub tcp_max_queries_per_connection: usize,
+    pub max_in_flight_queries: usize,
+    pub udp_queue_size: usize,
+    pub query_deadline_milliseconds: u64,
+    pub use_doh: bool,
+    pub doh_listen_addresses: Vec<SocketAddr>,
+    pub proxy_protocol_networks: Vec<IpNet>,
+    pub doh_trusted_proxies: Vec<IpNet>,
+    pub metrics_address: Option<SocketAddr>,
+    pub regions_file: String,
+    pub geoip_databases: Vec<String>,
+    pub geoip_reload_seconds: u64,
+    pub health_checks: bool,
+    pub health_check_interval_seconds: u64,
+    pub health_check_timeout_seconds: u64,
+    pub alias_resolver: Option<SocketAddr>,
+    pub any_over_tcp: bool,
+    pub ede_extra_text: bool,
+    pub cookie_secrets: String,
+    pub require_cookies: bool,
+    pub server_id: String,
+    pub use_nsid: bool,
+    pub chaos_names: Vec<String>,
+    pub format_errors_per_second: u32,
+    pub rrl_answers_per_second: u32,
+    pub rrl_nxdomains_per_second: u32,
+    pub rrl_errors_per_second: u32,
+    pub rrl_window_seconds: u32,
+    pub rrl_slip: u32,
+    pub rrl_log_only: bool,
+    pub rrl_ipv4_prefix: u8,
+    pub rrl_ipv6_prefix: u8,
+    pub rrl_max_table_size: usize,
+    pub tsig_keys_file: String,
+    pub views_file: String,
+    pub acls_file: String,
+    pub acl_reload_seconds: u64,
+}
+
+impl Config {
+    pub fn from_env() -> PektinResult<Self> {
+        // the bind address and port are the default listen address and the port of the listen
+        // addresses without one
+        let bind_address: IpAddr = load_env("::", "BIND_ADDRESS", false)?
+            .parse()
+            .map_err(|_| pektin_common::PektinCommonError::InvalidEnvVar("BIND_ADDRESS".into()))?;
+        let bind_port: u16 = load_env("53", "BIND_PORT", false)?
+            .parse()
+            .map_err(|_| pektin_common::PektinCommonError::InvalidEnvVar("BIND_PORT".into()))?;
+        let doh_bind_address: IpAddr =
+            load_env("::", "DOH_BIND_ADDRESS", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("DOH_BIND_ADDRESS".into())
+                })?;
+        let doh_bind_port: u16 = load_env("80", "DOH_BIND_PORT", false)?
+            .parse()
+            .map_err(|_| pektin_common::PektinCommonError::InvalidEnvVar("DOH_BIND_PORT".into()))?;
+
+        Ok(Self {
+            listen_addresses: parse_listen_addresses(
+                &load_env(
+                    &SocketAddr::from((bind_address, bind_port)).to_string(),
+                    "LISTEN_ADDRESSES",
+                    false,
+                )?,
+                bind_port,
+                "LISTEN_ADDRESSES",
+            )?,
+            db_hostname: load_env("pektin-db", "DB_HOSTNAME", false)?,
+            db_port: load_env("6379", "DB_PORT", false)?
+                .parse()
+                .map_err(|_| pektin_common::PektinCommonError::InvalidEnvVar("DB_PORT".into()))?,
+            db_username: load_env("db-pektin-server", "DB_USERNAME", false)?,
+            db_password: load_env("", "DB_PASSWORD", true)?,
+            db_retry_seconds: load_env("1", "DB_RETRY_SECONDS", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("DB_RETRY_SECONDS".into())
+                })?,
+            udp_sockets: load_env("0", "UDP_SOCKETS", false)?.parse().map_err(|_| {
+                pektin_common::PektinCommonError::InvalidEnvVar("UDP_SOCKETS".into())
+            })?,
+            udp_batch_size: load_env("1", "UDP_BATCH_SIZE", false)?
+                .parse()
+                .ok()
+                .filter(|&size| size > 0)
+                .ok_or_else(|| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("UDP_BATCH_SIZE".into())
+                })?,
+            tcp_timeout_seconds: load_env("3", "TCP_TIMEOUT_SECONDS", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("TCP_TIMEOUT_SECONDS".into())
+                })?,
+            tcp_max_in_flight: load_env("16", "TCP_MAX_IN_FLIGHT", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("TCP_MAX_IN_FLIGHT".into())
+                })?,
+            tcp_max_connections: load_env("1000", "TCP_MAX_CONNECTIONS", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("TCP_MAX_CONNECTIONS".into())
+                })?,
+            tcp_max_connections_per_client: load_env(
+                "32",
+                "TCP_MAX_CONNECTIONS_PER_CLIENT",
+                false,
+            )?
+            .parse()
+            .map_err(|_| {
+                pektin_common::PektinCommonError::InvalidEnvVar(
+                    "TCP_MAX_CONNECTIONS_PER_CLIENT".into(),
+                )
+            })?,
+            tcp_max_queries_per_connection: load_env("0", "TCP_MAX_QUERIES_PER_CONNECTION", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar(
+                        "TCP_MAX_QUERIES_PER_CONNECTION".into(),
+                    )
+                })?,
+            max_in_flight_queries: load_env("1024", "MAX_IN_FLIGHT_QUERIES", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("MAX_IN_FLIGHT_QUERIES".into())
+                })?,
+            udp_queue_size: load_env("1024", "UDP_QUEUE_SIZE", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("UDP_QUEUE_SIZE".into())
+                })?,
+            query_deadline_milliseconds: load_env("2000", "QUERY_DEADLINE_MILLISECONDS", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar(
+                        "QUERY_DEADLINE_MILLISECONDS".into(),
+                    )
+                })?,
+            use_doh: load_env("true", "USE_DOH", false)? == "true",
+            doh_listen_addresses: parse_listen_addresses(
+                &load_env(
+                    &SocketAddr::from((doh_bind_address, doh_bind_port)).to_string(),
+                    "DOH_LISTEN_ADDRESSES",
+                    false,
+                )?,
+                doh_bind_port,
+                "DOH_LISTEN_ADDRESSES",
+            )?,
+            proxy_protocol_networks: parse_networks(
+                &load_env("", "PROXY_PROTOCOL_NETWORKS", false)?,
+                "PROXY_PROTOCOL_NETWORKS",
+            )?,
+            doh_trusted_proxies: parse_networks(
+                &load_env("", "DOH_TRUSTED_PROXIES", false)?,
+                "DOH_TRUSTED_PROXIES",
+            )?,
+            metrics_address: match load_env("", "METRICS_ADDRESS", false)?.as_str() {
+                "" => None,
+                address => Some(address.parse().map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("METRICS_ADDRESS".into())
+                })?),
+            },
+            regions_file: load_env("", "REGIONS_FILE", false)?,
+            geoip_databases: load_env("", "GEOIP_DATABASES", false)?
+                .split(',')
+                .map(str::trim)
+                .filter(|path| !path.is_empty())
+                .map(String::from)
+                .collect(),
+            geoip_reload_seconds: load_env("60", "GEOIP_RELOAD_SECONDS", false)?
+                .parse()
+                .ok()
+                .filter(|&seconds| seconds > 0)
+                .ok_or_else(|| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("GEOIP_RELOAD_SECONDS".into())
+                })?,
+            health_checks: load_env("false", "HEALTH_CHECKS", false)? == "true",
+            health_check_interval_seconds: load_env("10", "HEALTH_CHECK_INTERVAL_SECONDS", false)?
+                .parse()
+                .ok()
+                .filter(|&seconds| seconds > 0)
+                .ok_or_else(|| {
+                    pektin_common::PektinCommonError::InvalidEnvVar(
+                        "HEALTH_CHECK_INTERVAL_SECONDS".into(),
+                    )
+                })?,
+            health_check_timeout_seconds: load_env("2", "HEALTH_CHECK_TIMEOUT_SECONDS", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar(
+                        "HEALTH_CHECK_TIMEOUT_SECONDS".into(),
+                    )
+                })?,
+            alias_resolver: match load_env("", "ALIAS_RESOLVER", false)?.as_str() {
+                "" => None,
+                resolver => Some(resolver.parse().map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("ALIAS_RESOLVER".into())
+                })?),
+            },
+            any_over_tcp: load_env("false", "ANY_OVER_TCP", false)? == "true",
+            ede_extra_text: load_env("false", "EDE_EXTRA_TEXT", false)? == "true",
+            cookie_secrets: load_env("", "COOKIE_SECRETS", true)?,
+            require_cookies: load_env("false", "REQUIRE_COOKIES", false)? == "true",
+            server_id: load_env(&hostname(), "SERVER_ID", false)?,
+            use_nsid: load_env("true", "USE_NSID", false)? == "true",
+            chaos_names: load_env("id.server.,hostname.bind.", "CHAOS_NAMES", false)?
+                .split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+                .filter(|name| !name.is_empty())
+                // names are compared in their fully qualified form
+                .map(|name| match name.ends_with('.') {
+                    true => name,
+                    false => format!("{}.", name),
+                })
+                .collect(),
+            format_errors_per_second: load_env("10", "FORMAT_ERRORS_PER_SECOND", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar(
+                        "FORMAT_ERRORS_PER_SECOND".into(),
+                    )
+                })?,
+            rrl_answers_per_second: load_env("0", "RRL_ANSWERS_PER_SECOND", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("RRL_ANSWERS_PER_SECOND".into())
+                })?,
+            rrl_nxdomains_per_second: load_env("0", "RRL_NXDOMAINS_PER_SECOND", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar(
+                        "RRL_NXDOMAINS_PER_SECOND".into(),
+                    )
+                })?,
+            rrl_errors_per_second: load_env("0", "RRL_ERRORS_PER_SECOND", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("RRL_ERRORS_PER_SECOND".into())
+                })?,
+            rrl_window_seconds: load_env("15", "RRL_WINDOW_SECONDS", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("RRL_WINDOW_SECONDS".into())
+                })?,
+            rrl_slip: load_env("2", "RRL_SLIP", false)?
+                .parse()
+                .map_err(|_| pektin_common::PektinCommonError::InvalidEnvVar("RRL_SLIP".into()))?,
+            rrl_log_only: load_env("false", "RRL_LOG_ONLY", false)? == "true",
+            rrl_ipv4_prefix: load_env("24", "RRL_IPV4_PREFIX", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("RRL_IPV4_PREFIX".into())
+                })?,
+            rrl_ipv6_prefix: load_env("56", "RRL_IPV6_PREFIX", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("RRL_IPV6_PREFIX".into())
+                })?,
+            rrl_max_table_size: load_env("100000", "RRL_MAX_TABLE_SIZE", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("RRL_MAX_TABLE_SIZE".into())
+                })?,
+            tsig_keys_file: load_env("", "TSIG_KEYS_FILE", false)?,
+            views_file: load_env("", "VIEWS_FILE", false)?,
+            acls_file: load_env("", "ACLS_FILE", false)?,
+            acl_reload_seconds: load_env("10", "ACL_RELOAD_SECONDS", false)?
+                .parse()
+                .ok()
+                .filter(|&seconds| seconds > 0)
+                .ok_or_else(|| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("ACL_RELOAD_SECONDS".into())
+                })?,
+        })
+    }
+
+    /// Creates a connection pool for the db with the given number.
+    pub fn create_db_pool(&self, db: u8) -> PektinResult<Pool> {
+        let db_pool_conf = deadpool_redis::Config {
+            url: Some(format!(
+                "redis://{}:{}@{}:{}/{}",
+                self.db_username, self.db_password, self.db_hostname, self.db_port, db
+            )),
+            connection: None,
+            pool: None,
+        };
+        Ok(db_pool_conf.create_pool(Some(deadpool_redis::Runtime::Tokio1))?)
+    }
+}
+
+/// Parses a comma-separated list of IPv4 and IPv6 addresses with optional ports, e.g.
+/// `192.0.2.1,[2001:db8::1]:5353,2001:db8::2`.
+fn parse_listen_addresses(
+    list: &str,
+    default_port: u16,
+    param_name: &str,
+) -> PektinResult<Vec<SocketAddr>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(|address| {
+            address
+                .parse::<SocketAddr>()
+                .or_else(|_| {
+                    address
+                        .parse::<IpAddr>()
+                        .map(|ip| (ip, default_port).into())
+                })
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar(param_name.into()).into()
+                })
+        })
+        .collect()
+}
+
+/// Parses a comma-separated list of networks in CIDR notation, e.g. `10.0.0.0/8,2001:db8::/32`.
+fn parse_networks(list: &str, param_name: &str) -> PektinResult<Vec<IpNet>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|net| !net.is_empty())
+        .map(|net| {
+            net.parse::<IpNet>().map(|net| net.trunc()).map_err(|_| {
+                pektin_common::PektinCommonError::InvalidEnvVar(param_name.into()).into()
+            })
+        })
+        .collect()
+}
+
+/// Returns the hostname of the machine (or container) the server runs on.
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|hostname| hostname.trim().to_string())
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_listen_addresses_with_and_without_ports() {
+        let addresses =
+            parse_listen_addresses(" 192.0.2.1, [2001:db8::1]:5353,2001:db8::2,", 53, "TEST")
+                .unwrap();
+        assert_eq!(
+            addresses,
+            vec![
+                "192.0.2.1:53".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:5353".parse().unwrap(),
+                "[2001:db8::2]:53".parse().unwrap(),
+            ]
+        );
+        assert!(parse_listen_addresses("", 53, "TEST").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_listen_addresses() {
+        assert!(parse_listen_addresses("localhost", 53, "TEST").is_err());
+        assert!(parse_listen_addresses("192.0.2.1:http", 53, "TEST").is_err());
+        assert!(parse_listen_addresses("2001:db8::1:53:", 53, "TEST").is_err());
+    }
+}
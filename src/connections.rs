@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use log::info;
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+use crate::canonical_ip;
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+struct Connection {
+    client_ip: IpAddr,
+    last_active: Instant,
+    evicted: Arc<Notify>,
+}
+
+/// Keeps track of the open TCP connections to limit their number, both in total and per client
+/// address.
+///
+/// When a limit is hit, the connection that has been idle the longest is evicted to make room for
+/// the new one, so that a flood of connections can't lock out other clients for long.
+pub struct TcpConnections {
+    /// 0 means unlimited.
+    max_connections: usize,
+    /// 0 means unlimited.
+    max_connections_per_client: usize,
+    connections: Mutex<(HashMap<u64, Connection>, u64)>,
+}
+
+impl TcpConnections {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_connections: config.tcp_max_connections,
+            max_connections_per_client: config.tcp_max_connections_per_client,
+            connections: Mutex::new((HashMap::new(), 0)),
+        }
+    }
+
+    /// Registers a new connection from the given client, evicting others if necessary.
+    ///
+    /// The connection is unregistered when the returned handle is dropped.
+    pub fn open(&self, client_ip: IpAddr, metrics: &Metrics) -> TcpConnection<'_> {
+        let client_ip = canonical_ip(client_ip);
+        let mut guard = self.connections.lock();
+        let (connections, next_id) = &mut *guard;
+
+        if self.max_connections_per_client != 0
+            && connections
+                .values()
+                .filter(|c| c.client_ip == client_ip)
+                .count()
+                >= self.max_connections_per_client
+        {
+            evict_oldest(connections, Some(client_ip), client_ip, metrics);
+        }
+        if self.max_connections != 0 && connections.len() >= self.max_connections {
+            evict_oldest(connections, None, client_ip, metrics);
+        }
+
+        let id = *next_id;
+        *next_id += 1;
+        let evicted = Arc::new(Notify::new());
+        connections.insert(
+            id,
+            Connection {
+                client_ip,
+                last_active: Instant::now(),
+                evicted: evicted.clone(),
+            },
+        );
+        TcpConnection {
+            id,
+            evicted,
+            connections: self,
+        }
+    }
+}
+
+/// Evicts the connection that has been idle the longest, only considering the connections from
+/// `of_client` if it's given.
+fn evict_oldest(
+    connections: &mut HashMap<u64, Connection>,
+    of_client: Option<IpAddr>,
+    new_client: IpAddr,
+    metrics: &Metrics,
+) {
+    let oldest = connections
+        .iter()
+        .filter(|(_, c)| of_client.is_none_or(|ip| c.client_ip == ip))
+        .min_by_key(|(_, c)| c.last_active)
+        .map(|(id, _)| *id);
+    if let Some(connection) = oldest.and_then(|id| connections.remove(&id)) {
+        info!(
+            "Evicting idle TCP connection from {} to make room for {}",
+            connection.client_ip, new_client
+        );
+        connection.evicted.notify_one();
+        metrics
+            .evicted_tcp_connections
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A connection registered in [`TcpConnections`].
+pub struct TcpConnection<'a> {
+    id: u64,
+    evicted: Arc<Notify>,
+    connections: &'a TcpConnections,
+}
+
+impl TcpConnection<'_> {
+    /// Marks the connection as active, e.g. when a query was received on it.
+    pub fn touch(&self) {
+        if let Some(connection) = self.connections.connections.lock().0.get_mut(&self.id) {
+            connection.last_active = Instant::now();
+        }
+    }
+
+    /// Completes when the connection was evicted and should be closed.
+    pub async fn evicted(&self) {
+        self.evicted.notified().await
+    }
+}
+
+impl Drop for TcpConnection<'_> {
+    fn drop(&mut self) {
+        self.connections.connections.lock().0.remove(&self.id);
+    }
+}
+
+/// A connection whose reading side can be closed while writing to it continues.
+///
+/// Once [`ReadCloser::close`] was called, reads return the end of the stream, so that the
+/// remaining responses can be written before closing the connection without reading more queries.
+pub struct ClosableReads<S> {
+    stream: S,
+    closed: Arc<AtomicBool>,
+}
+
+/// Closes the reading side of a [`ClosableReads`] connection.
+#[derive(Clone)]
+pub struct ReadCloser(Arc<AtomicBool>);
+
+impl ReadCloser {
+    pub fn close(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<S> ClosableReads<S> {
+    pub fn new(stream: S) -> (Self, ReadCloser) {
+        let closed = Arc::new(AtomicBool::new(false));
+        let closer = ReadCloser(closed.clone());
+        (Self { stream, closed }, closer)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ClosableReads<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.closed.load(Ordering::Relaxed) {
+            // reading nothing signals the end of the stream
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ClosableReads<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::FutureExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn tcp_connections(
+        max_connections: usize,
+        max_connections_per_client: usize,
+    ) -> TcpConnections {
+        TcpConnections {
+            max_connections,
+            max_connections_per_client,
+            connections: Mutex::new((HashMap::new(), 0)),
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn is_evicted(connection: &TcpConnection<'_>) -> bool {
+        connection.evicted().now_or_never().is_some()
+    }
+
+    fn open_count(connections: &TcpConnections) -> usize {
+        connections.connections.lock().0.len()
+    }
+
+    /// Makes sure that connections opened or touched after this have a later activity time.
+    fn tick() {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    #[test]
+    fn evicts_the_oldest_idle_connection_of_the_client() {
+        let connections = tcp_connections(0, 2);
+        let metrics = Metrics::default();
+        let first = connections.open(ip("192.0.2.1"), &metrics);
+        tick();
+        let second = connections.open(ip("192.0.2.1"), &metrics);
+        let other_client = connections.open(ip("192.0.2.2"), &metrics);
+        tick();
+        first.touch();
+        tick();
+
+        // IPv4-mapped addresses count as the IPv4 address
+        let third = connections.open(ip("::ffff:192.0.2.1"), &metrics);
+        assert!(is_evicted(&second));
+        assert!(!is_evicted(&first));
+        assert!(!is_evicted(&third));
+        assert!(!is_evicted(&other_client));
+        assert_eq!(metrics.evicted_tcp_connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn evicts_the_oldest_idle_connection_of_all_clients() {
+        let connections = tcp_connections(2, 0);
+        let metrics = Metrics::default();
+        let first = connections.open(ip("192.0.2.1"), &metrics);
+        tick();
+        let second = connections.open(ip("192.0.2.2"), &metrics);
+        tick();
+
+        let third = connections.open(ip("192.0.2.3"), &metrics);
+        assert!(is_evicted(&first));
+        assert!(!is_evicted(&second));
+        assert!(!is_evicted(&third));
+        assert_eq!(open_count(&connections), 2);
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let connections = tcp_connections(0, 0);
+        let metrics = Metrics::default();
+        let open: Vec<_> = (0..10)
+            .map(|_| connections.open(ip("192.0.2.1"), &metrics))
+            .collect();
+        assert!(open.iter().all(|connection| !is_evicted(connection)));
+        assert_eq!(open_count(&connections), 10);
+    }
+
+    #[test]
+    fn dropped_connections_are_unregistered() {
+        let connections = tcp_connections(1, 0);
+        let metrics = Metrics::default();
+        drop(connections.open(ip("192.0.2.1"), &metrics));
+        assert_eq!(open_count(&connections), 0);
+
+        let connection = connections.open(ip("192.0.2.2"), &metrics);
+        assert!(!is_evicted(&connection));
+        assert_eq!(metrics.evicted_tcp_connections.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn closed_reads_end_the_stream_but_writes_continue() {
+        let (client, server) = tokio::io::duplex(64);
+        let (mut client, mut server) = (client, ClosableReads::new(server));
+        client.write_all(b"query").await.unwrap();
+        let mut buf = [0; 5];
+        server.0.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"query");
+
+        client.write_all(b"another query").await.unwrap();
+        server.1.close();
+        assert_eq!(server.0.read(&mut buf).await.unwrap(), 0);
+        server.0.write_all(b"response").await.unwrap();
+        let mut response = [0; 8];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"response");
+    }
+}
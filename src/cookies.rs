@@ -0,0 +1,265 @@
+use std::hash::Hasher;
+use std::net::IpAddr;
+
+use data_encoding::HEXLOWER_PERMISSIVE;
+use pektin_common::proto::op::Message;
+use pektin_common::proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use siphasher::sip::SipHasher24;
+
+use crate::{canonical_ip, PektinError, PektinResult};
+
+const CLIENT_COOKIE_LEN: usize = 8;
+/// The length of the server cookies we generate (see RFC 9018, section 4).
+const SERVER_COOKIE_LEN: usize = 16;
+const SERVER_COOKIE_VERSION: u8 = 1;
+/// How long a server cookie is valid after it was generated.
+const MAX_COOKIE_AGE_SECONDS: u32 = 3600;
+/// How far in the future the timestamp of a server cookie may be, to allow for clock skew between
+/// servers.
+const MAX_COOKIE_SKEW_SECONDS: u32 = 300;
+
+/// The COOKIE option of a request (see RFC 7873).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestCookie {
+    pub client_cookie: [u8; CLIENT_COOKIE_LEN],
+    pub server_cookie: Option<Vec<u8>>,
+}
+
+impl RequestCookie {
+    /// Extracts the cookie option from the given query message.
+    ///
+    /// Returns `Ok(None)` if the message doesn't contain the option. If the option is malformed, a
+    /// response with [`ResponseCode::FormErr`] should be sent (see RFC 7873, section 5.2.2).
+    ///
+    /// [`ResponseCode::FormErr`]: pektin_common::proto::op::ResponseCode::FormErr
+    pub fn from_message(message: &Message) -> PektinResult<Option<Self>> {
+        let option = message
+            .extensions()
+            .as_ref()
+            .and_then(|edns| edns.option(EdnsCode::Cookie));
+        let data = match option {
+            Some(EdnsOption::Unknown(_, data)) => data,
+            Some(_) => return Err(PektinError::MalformedEdnsOption("cookie")),
+            None => return Ok(None),
+        };
+        // the server cookie must be between 8 and 32 bytes long
+        if data.len() != CLIENT_COOKIE_LEN && !(16..=40).contains(&data.len()) {
+            return Err(PektinError::MalformedEdnsOption("cookie"));
+        }
+
+        let mut client_cookie = [0; CLIENT_COOKIE_LEN];
+        client_cookie.copy_from_slice(&data[..CLIENT_COOKIE_LEN]);
+        let server_cookie =
+            (data.len() > CLIENT_COOKIE_LEN).then(|| data[CLIENT_COOKIE_LEN..].to_vec());
+        Ok(Some(Self {
+            client_cookie,
+            server_cookie,
+        }))
+    }
+}
+
+/// The secrets used to generate and verify server cookies.
+///
+/// All servers of an anycast deployment should use the same secrets so that a server cookie
+/// generated by one server is accepted by the others.
+pub struct CookieSecrets {
+    /// The first secret is used to generate cookies, the others are only used to verify them,
+    /// which allows rotating the secret without invalidating the cookies clients have.
+    secrets: Vec<[u8; 16]>,
+}
+
+impl CookieSecrets {
+    /// Parses the comma-separated list of hex-encoded 128-bit secrets.
+    ///
+    /// If the list is empty, a random secret is used.
+    pub fn parse(secrets: &str) -> PektinResult<Self> {
+        let secrets: Vec<_> = secrets
+            .split(',')
+            .map(str::trim)
+            .filter(|secret| !secret.is_empty())
+            .map(|secret| {
+                HEXLOWER_PERMISSIVE
+                    .decode(secret.as_bytes())
+                    .ok()
+                    .and_then(|secret| secret.try_into().ok())
+                    .ok_or_else(|| {
+                        PektinError::InvalidConfig(
+                            "cookie secrets must be 128 bits, hex-encoded".into(),
+                        )
+                    })
+            })
+            .collect::<PektinResult<_>>()?;
+        if secrets.is_empty() {
+            return Ok(Self::random());
+        }
+        Ok(Self { secrets })
+    }
+
+    /// Uses a random secret, which means that only this server accepts its cookies.
+    pub fn random() -> Self {
+        Self {
+            secrets: vec![rand::random()],
+        }
+    }
+
+    /// Checks that the server cookie of the request was generated by us for this client within
+    /// the last hour.
+    pub fn verify(&self, cookie: &RequestCookie, client_ip: IpAddr, now: u32) -> bool {
+        let server_cookie = match &cookie.server_cookie {
+            Some(c) if c.len() == SERVER_COOKIE_LEN && c[0] == SERVER_COOKIE_VERSION => c,
+            _ => return false,
+        };
+        let timestamp = u32::from_be_bytes(server_cookie[4..8].try_into().unwrap());
+        if timestamp > now.saturating_add(MAX_COOKIE_SKEW_SECONDS)
+            || now.saturating_sub(timestamp) > MAX_COOKIE_AGE_SECONDS
+        {
+            return false;
+        }
+
+        self.secrets.iter().any(|secret| {
+            let hash = cookie_hash(
+                secret,
+                &cookie.client_cookie,
+                &server_cookie[..8],
+                client_ip,
+            );
+            constant_time_eq(&hash, &server_cookie[8..])
+        })
+    }
+
+    /// Generates a server cookie for the client and returns the COOKIE option for the response.
+    pub fn response_option(
+        &self,
+        cookie: &RequestCookie,
+        client_ip: IpAddr,
+        now: u32,
+    ) -> EdnsOption {
+        // version, 3 reserved bytes and the timestamp
+        let mut server_cookie = vec![SERVER_COOKIE_VERSION, 0, 0, 0];
+        server_cookie.extend_from_slice(&now.to_be_bytes());
+        let hash = cookie_hash(
+            &self.secrets[0],
+            &cookie.client_cookie,
+            &server_cookie,
+            client_ip,
+        );
+
+        let mut data = cookie.client_cookie.to_vec();
+        data.extend_from_slice(&server_cookie);
+        data.extend_from_slice(&hash);
+        EdnsOption::Unknown(EdnsCode::Cookie.into(), data)
+    }
+}
+
+/// Calculates the hash part of a server cookie (see RFC 9018, section 4.4).
+///
+/// `header` contains the version, reserved and timestamp fields of the server cookie.
+fn cookie_hash(
+    secret: &[u8; 16],
+    client_cookie: &[u8],
+    header: &[u8],
+    client_ip: IpAddr,
+) -> [u8; 8] {
+    let mut hasher = SipHasher24::new_with_key(secret);
+    hasher.write(client_cookie);
+    hasher.write(header);
+    match canonical_ip(client_ip) {
+        IpAddr::V4(ip) => hasher.write(&ip.octets()),
+        IpAddr::V6(ip) => hasher.write(&ip.octets()),
+    }
+    hasher.finish().to_le_bytes()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The values of the first example in RFC 9018, appendix A.
+    const SECRET: &str = "e5e973e5a6b2a43f48e7dc849e37bfcf";
+    const CLIENT_COOKIE: [u8; 8] = [0x24, 0x64, 0xc4, 0xab, 0xcf, 0x10, 0xc9, 0x57];
+    const TIMESTAMP: u32 = 1559731985;
+
+    fn client_ip() -> IpAddr {
+        "198.51.100.100".parse().unwrap()
+    }
+
+    fn request(server_cookie: Option<Vec<u8>>) -> RequestCookie {
+        RequestCookie {
+            client_cookie: CLIENT_COOKIE,
+            server_cookie,
+        }
+    }
+
+    fn server_cookie(option: EdnsOption) -> Vec<u8> {
+        match option {
+            EdnsOption::Unknown(_, data) => data[CLIENT_COOKIE_LEN..].to_vec(),
+            option => panic!("unexpected option {:?}", option),
+        }
+    }
+
+    #[test]
+    fn generates_the_rfc_9018_example() {
+        let secrets = CookieSecrets::parse(SECRET).unwrap();
+        let option = secrets.response_option(&request(None), client_ip(), TIMESTAMP);
+        assert_eq!(
+            HEXLOWER_PERMISSIVE.encode(&server_cookie(option)),
+            "010000005cf79f111f8130c3eee29480"
+        );
+    }
+
+    #[test]
+    fn verifies_own_cookies() {
+        let secrets = CookieSecrets::parse(SECRET).unwrap();
+        let option = secrets.response_option(&request(None), client_ip(), TIMESTAMP);
+        let cookie = request(Some(server_cookie(option)));
+
+        assert!(secrets.verify(&cookie, client_ip(), TIMESTAMP));
+        assert!(secrets.verify(&cookie, client_ip(), TIMESTAMP + MAX_COOKIE_AGE_SECONDS));
+        // IPv4-mapped addresses are treated like the IPv4 address
+        assert!(secrets.verify(&cookie, "::ffff:198.51.100.100".parse().unwrap(), TIMESTAMP));
+    }
+
+    #[test]
+    fn rejects_invalid_cookies() {
+        let secrets = CookieSecrets::parse(SECRET).unwrap();
+        let option = secrets.response_option(&request(None), client_ip(), TIMESTAMP);
+        let cookie = request(Some(server_cookie(option)));
+
+        // too old or from the future
+        let expired = TIMESTAMP + MAX_COOKIE_AGE_SECONDS + 1;
+        assert!(!secrets.verify(&cookie, client_ip(), expired));
+        let early = TIMESTAMP - MAX_COOKIE_SKEW_SECONDS - 1;
+        assert!(!secrets.verify(&cookie, client_ip(), early));
+        // another client
+        assert!(!secrets.verify(&cookie, "198.51.100.101".parse().unwrap(), TIMESTAMP));
+        // another secret
+        let other = CookieSecrets::parse("00000000000000000000000000000000").unwrap();
+        assert!(!other.verify(&cookie, client_ip(), TIMESTAMP));
+        // no server cookie
+        assert!(!secrets.verify(&request(None), client_ip(), TIMESTAMP));
+    }
+
+    #[test]
+    fn old_secrets_are_only_used_for_verification() {
+        let old = CookieSecrets::parse(SECRET).unwrap();
+        let option = old.response_option(&request(None), client_ip(), TIMESTAMP);
+        let cookie = request(Some(server_cookie(option)));
+
+        let rotated =
+            CookieSecrets::parse(&format!("00000000000000000000000000000000,{}", SECRET)).unwrap();
+        assert!(rotated.verify(&cookie, client_ip(), TIMESTAMP));
+        let option = rotated.response_option(&request(None), client_ip(), TIMESTAMP);
+        assert_ne!(Some(server_cookie(option)), cookie.server_cookie);
+    }
+
+    #[test]
+    fn rejects_invalid_secrets() {
+        assert!(CookieSecrets::parse("e5e973e5").is_err());
+        assert!(CookieSecrets::parse("not hex").is_err());
+        assert_eq!(CookieSecrets::parse(" , ").unwrap().secrets.len(), 1);
+    }
+}
@@ -0,0 +1,116 @@
+use pektin_common::proto::rr::rdata::NULL;
+use pektin_common::proto::rr::{Name, RData, Record, RecordType};
+use pektin_common::proto::serialize::binary::{BinEncodable, BinEncoder};
+use serde::Deserialize;
+
+use crate::PektinResult;
+
+/// The type code of DNAME records, which trust-dns doesn't know.
+const DNAME_TYPE: u16 = 39;
+
+/// Returns whether the given type is DNAME, which trust-dns parses as [`RecordType::Unknown`].
+pub fn is_dname_type(rr_type: RecordType) -> bool {
+    u16::from(rr_type) == DNAME_TYPE
+}
+
+/// A DNAME record (see RFC 6672), stored as JSON at `<name>:DNAME`.
+///
+/// ```json
+/// { "target": "new.example.com.", "ttl": 300 }
+/// ```
+///
+/// Its RRSIG records are stored at `<name>:RRSIG:DNAME` in the DNSSEC db.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Dname {
+    pub target: String,
+    pub ttl: u32,
+}
+
+impl Dname {
+    pub fn target(&self) -> PektinResult<Name> {
+        Ok(Name::from_utf8(&self.target)?)
+    }
+
+    /// Converts the DNAME into a record with the given owner name.
+    pub fn to_record(&self, owner: Name) -> PektinResult<Record> {
+        let mut rdata = vec![];
+        let mut encoder = BinEncoder::new(&mut rdata);
+        // names in the RDATA of unknown types must not be compressed (see RFC 3597, section 4)
+        encoder.set_canonical_names(true);
+        self.target()?.emit(&mut encoder)?;
+        Ok(Record::from_rdata(
+            owner,
+            self.ttl,
+            RData::Unknown {
+                code: DNAME_TYPE,
+                rdata: NULL::with(rdata),
+            },
+        ))
+    }
+
+    /// Returns the name that `name` is redirected to by this DNAME at `owner`, or `None` if that
+    /// name would be too long.
+    pub fn redirect(&self, name: &Name, owner: &Name) -> PektinResult<Option<Name>> {
+        let prefix_labels = name.num_labels() - owner.num_labels();
+        let prefix = Name::from_labels(name.iter().take(prefix_labels as usize))?;
+        Ok(prefix.append_domain(&self.target()?).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pektin_common::proto::op::Query;
+    use pektin_common::proto::serialize::binary::BinDecodable;
+
+    use super::*;
+
+    fn dname(target: &str) -> Dname {
+        Dname {
+            target: target.into(),
+            ttl: 300,
+        }
+    }
+
+    fn name(name: &str) -> Name {
+        Name::from_ascii(name).unwrap()
+    }
+
+    #[test]
+    fn redirects_below_the_target() {
+        let redirected = dname("new.example.net.")
+            .redirect(&name("www.Sub.old.example.com."), &name("old.example.com."))
+            .unwrap();
+        assert_eq!(redirected, Some(name("www.Sub.new.example.net.")));
+    }
+
+    #[test]
+    fn redirected_name_is_limited_to_255_bytes() {
+        let label = "a".repeat(63);
+        let long_name = name(&format!("{0}.{0}.{0}.old.", label));
+        let target = format!("{}.{}.", label, "b".repeat(60));
+        let redirected = dname(&target).redirect(&long_name, &name("old.")).unwrap();
+        assert_eq!(redirected, None);
+    }
+
+    #[test]
+    fn recognizes_dname_queries() {
+        let query = Query::query(name("old.example.com."), RecordType::from(DNAME_TYPE));
+        let query = Query::from_bytes(&query.to_bytes().unwrap()).unwrap();
+        assert!(is_dname_type(query.query_type()));
+        assert!(!is_dname_type(RecordType::CNAME));
+    }
+
+    #[test]
+    fn record_contains_the_uncompressed_target() {
+        let record = dname("b.").to_record(name("a.")).unwrap();
+        assert_eq!(record.name(), &name("a."));
+        assert_eq!(record.ttl(), 300);
+        match record.data() {
+            Some(RData::Unknown { code, rdata }) => {
+                assert_eq!(*code, DNAME_TYPE);
+                assert_eq!(rdata.anything(), &[1, b'b', 0][..]);
+            }
+            data => panic!("unexpected RDATA {:?}", data),
+        }
+    }
+}
@@ -1,55 +1,115 @@
-use crate::{process_request, PektinResult};
+use crate::{
+    format_error_response, listeners, process_request, proxy, PektinResult, RequestInfo,
+    ServerState, Transport,
+};
 use actix_cors::Cors;
-use actix_web::dev::Server;
-use actix_web::{get, post, web, App, HttpResponse, HttpServer};
+use actix_http::error::DispatchError;
+use actix_http::{HttpService, Protocol};
+use actix_service::{map_config, ServiceFactoryExt};
+use actix_web::body::MessageBody;
+use actix_web::dev::{
+    fn_service, AppConfig, Server, ServiceFactory, ServiceRequest, ServiceResponse,
+};
+use actix_web::rt::net::TcpStream;
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse};
 use data_encoding::BASE64URL_NOPAD;
-use pektin_common::deadpool_redis::Pool;
+use ipnet::IpNet;
 use pektin_common::proto::op::Message;
 use serde::Deserialize;
-use std::net::Ipv6Addr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Deserialize)]
 struct GetQueries {
     dns: String,
 }
 
-struct AppState {
-    pub db_pool: Pool,
-    pub db_pool_dnssec: Pool,
+/// Starts the DoH server on the given addresses.
+///
+/// Instead of `HttpServer`, the connections are accepted by our own listeners, so that the PROXY
+/// protocol header of connections from trusted load balancers can be read before the HTTP request.
+pub async fn use_doh(addresses: &[SocketAddr], state: Arc<ServerState>) -> PektinResult<Server> {
+    let mut server = Server::build();
+    let v6_only = listeners::v6_only(addresses);
+    for &address in addresses {
+        let state = state.clone();
+        let listener = listeners::bind_tcp(address, v6_only)?;
+        server = server.listen(format!("doh-{}", address), listener, move || {
+            let app = App::new()
+                .wrap(
+                    Cors::default()
+                        .allow_any_origin()
+                        .allowed_header("content-type")
+                        .allowed_methods(vec!["GET", "POST"]),
+                )
+                .app_data(web::Data::from(state.clone()))
+                .service(doh_post)
+                .service(doh_get);
+            http_service(
+                app,
+                address,
+                Arc::new(state.config.proxy_protocol_networks.clone()),
+                Duration::from_secs(state.config.tcp_timeout_seconds),
+            )
+        })?;
+    }
+    Ok(server.run())
 }
 
-pub async fn use_doh(
-    bind_address: Ipv6Addr,
-    bind_port: u16,
-    db_pool: Pool,
-    db_pool_dnssec: Pool,
-) -> PektinResult<Server> {
-    Ok(HttpServer::new(move || {
-        App::new()
-            .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allowed_header("content-type")
-                    .allowed_methods(vec!["GET", "POST"]),
-            )
-            .app_data(web::Data::new(AppState {
-                db_pool: db_pool.clone(),
-                db_pool_dnssec: db_pool_dnssec.clone(),
-            }))
-            .service(doh_post)
-            .service(doh_get)
+/// Serves the app over HTTP/1.x on the connections accepted at `local_addr`.
+///
+/// Connections from peers in `proxy_networks` must start with a PROXY protocol header, whose
+/// source address is then the peer address of the requests.
+fn http_service<T, B>(
+    app: App<T>,
+    local_addr: SocketAddr,
+    proxy_networks: Arc<Vec<IpNet>>,
+    header_timeout: Duration,
+) -> impl ServiceFactory<TcpStream, Config = (), Response = (), Error = DispatchError, InitError = ()>
+where
+    T: ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    let http_service = HttpService::build()
+        .local_addr(local_addr)
+        .finish(map_config(app, |_| AppConfig::default()));
+    fn_service(move |mut stream: TcpStream| {
+        let proxy_networks = proxy_networks.clone();
+        async move {
+            let peer_addr = stream.peer_addr()?;
+            // behind a load balancer, the connection starts with the address of the actual client
+            let client_addr =
+                proxy::client_addr(&mut stream, peer_addr, &proxy_networks, header_timeout)
+                    .await
+                    .ok_or(DispatchError::Io(std::io::ErrorKind::InvalidData.into()))?;
+            Ok((stream, Protocol::Http1, Some(client_addr)))
+        }
     })
-    .bind((bind_address, bind_port))?
-    .run())
+    .and_then(http_service)
 }
 
 #[post("/dns-query")]
-async fn doh_post(body: web::Bytes, state: web::Data<AppState>) -> HttpResponse {
-    handle_request(&body, state).await
+async fn doh_post(
+    req: HttpRequest,
+    body: web::Bytes,
+    state: web::Data<ServerState>,
+) -> HttpResponse {
+    handle_request(&req, &body, state).await
 }
 
 #[get("/dns-query")]
-async fn doh_get(queries: web::Query<GetQueries>, state: web::Data<AppState>) -> HttpResponse {
+async fn doh_get(
+    req: HttpRequest,
+    queries: web::Query<GetQueries>,
+    state: web::Data<ServerState>,
+) -> HttpResponse {
     let query_bytes = match BASE64URL_NOPAD.decode(queries.dns.as_bytes()) {
         Ok(b) => b,
         Err(e) => {
@@ -58,24 +118,42 @@ async fn doh_get(queries: web::Query<GetQueries>, state: web::Data<AppState>) ->
                 .body(format!("Invalid Base64: {e}"))
         }
     };
-    handle_request(&query_bytes, state).await
+    handle_request(&req, &query_bytes, state).await
 }
 
-async fn handle_request(bytes: &[u8], state: web::Data<AppState>) -> HttpResponse {
+async fn handle_request(
+    req: &HttpRequest,
+    bytes: &[u8],
+    state: web::Data<ServerState>,
+) -> HttpResponse {
     let message = match Message::from_vec(bytes) {
         Ok(m) => m,
         Err(e) => {
+            return match format_error_response(bytes, &state) {
+                Some(response_bytes) => HttpResponse::Ok()
+                    .content_type("application/dns-message")
+                    .body(response_bytes),
+                None => HttpResponse::BadRequest()
+                    .content_type("application/dns-message")
+                    .body(format!("Invalid DNS message: {e}")),
+            }
+        }
+    };
+
+    let request_info = RequestInfo {
+        transport: Transport::Https,
+        client_ip: client_ip(req, &state.config.doh_trusted_proxies),
+        raw_message: bytes,
+    };
+    let response = match process_request(message, request_info, &state).await {
+        Some(r) => r,
+        None => {
             return HttpResponse::BadRequest()
                 .content_type("application/dns-message")
-                .body(format!("Invalid DNS message: {e}"))
+                .body("Received a response message instead of a query")
         }
     };
-
-    match process_request(message, state.db_pool.clone(), state.db_pool_dnssec.clone())
-        .await
-        .to_vec()
-        .ok()
-    {
+    match response.to_vec().ok() {
         Some(bytes) => HttpResponse::Ok()
             .content_type("application/dns-message")
             .body(bytes),
@@ -84,3 +162,198 @@ async fn handle_request(bytes: &[u8], state: web::Data<AppState>) -> HttpRespons
             .body("Could not process request"),
     }
 }
+
+/// Returns the address of the client.
+///
+/// If the request comes from one of the trusted proxies, the address is taken from the
+/// `Forwarded` header (see RFC 7239) or, if there is none, the `X-Forwarded-For` header. Their
+/// addresses are checked from the last one, which was added by the proxy closest to us, and the
+/// first one not belonging to a trusted proxy is the client, as all before it could be forged.
+fn client_ip(req: &HttpRequest, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let peer_ip = req.peer_addr()?.ip();
+    if !proxy::is_trusted(peer_ip, trusted_proxies) {
+        return Some(peer_ip);
+    }
+
+    let header_values = |name: &str| {
+        req.headers()
+            .get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>()
+    };
+    let forwarded = header_values("forwarded");
+    let hops: Vec<Option<IpAddr>> = if forwarded.is_empty() {
+        header_values("x-forwarded-for")
+            .into_iter()
+            .map(parse_node)
+            .collect()
+    } else {
+        forwarded
+            .into_iter()
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then(|| parse_node(value))?
+                })
+            })
+            .collect()
+    };
+
+    let mut client_ip = peer_ip;
+    for hop in hops.into_iter().rev() {
+        match hop {
+            Some(ip) => {
+                client_ip = ip;
+                if !proxy::is_trusted(ip, trusted_proxies) {
+                    break;
+                }
+            }
+            // e.g. "unknown" or an obfuscated identifier, so we can't know who is behind it
+            None => break,
+        }
+    }
+    Some(client_ip)
+}
+
+/// Parses an address from a forwarding header, which may be quoted and have a port, with IPv6
+/// addresses in brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn trusted_proxies() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap()]
+    }
+
+    #[test]
+    fn parses_nodes() {
+        assert_eq!(parse_node("192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node(" 192.0.2.1:4711"), Some(ip("192.0.2.1")));
+        assert_eq!(
+            parse_node("\"[2001:db8::1]:4711\""),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let req = TestRequest::default()
+            .peer_addr("192.0.2.1:4711".parse().unwrap())
+            .insert_header(("x-forwarded-for", "198.51.100.1"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, &trusted_proxies()), Some(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn uses_the_last_untrusted_forwarded_address() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:4711".parse().unwrap())
+            .insert_header((
+                "forwarded",
+                "for=203.0.113.1, for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2",
+            ))
+            .insert_header(("x-forwarded-for", "198.51.100.1"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, &trusted_proxies()), Some(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn falls_back_to_x_forwarded_for() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:4711".parse().unwrap())
+            .insert_header(("x-forwarded-for", "198.51.100.1, 192.0.2.1, 10.0.0.2"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, &trusted_proxies()), Some(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn stops_at_unknown_hops() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:4711".parse().unwrap())
+            .insert_header(("forwarded", "for=192.0.2.1, for=unknown"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, &trusted_proxies()), Some(ip("10.0.0.1")));
+    }
+
+    /// Starts a server that answers every request with the peer address of the request.
+    fn peer_addr_server(proxy_networks: Vec<IpNet>) -> SocketAddr {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy_networks = Arc::new(proxy_networks);
+        let server = Server::build()
+            .workers(1)
+            .disable_signals()
+            .listen("test", listener, move || {
+                let app = App::new().default_service(web::to(|req: HttpRequest| async move {
+                    HttpResponse::Ok().body(req.peer_addr().unwrap().to_string())
+                }));
+                http_service(app, addr, proxy_networks.clone(), Duration::from_secs(1))
+            })
+            .unwrap()
+            .run();
+        actix_web::rt::spawn(server);
+        addr
+    }
+
+    /// Sends a request after the given bytes and returns the whole response.
+    async fn get(addr: SocketAddr, before_request: &[u8]) -> std::io::Result<String> {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(before_request).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[actix_web::test]
+    async fn takes_the_peer_address_from_proxy_headers() {
+        // PROXY from 192.0.2.1:5353 to 198.51.100.1:443
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0x14, 0xe9, 0x01, 0xbb]);
+
+        let addr = peer_addr_server(vec!["127.0.0.0/8".parse().unwrap()]);
+        let response = get(addr, &header).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n192.0.2.1:5353"), "{}", response);
+
+        // trusted peers must send the header, otherwise the connection is closed (with a reset
+        // because of the unread request)
+        let response = get(addr, b"").await;
+        assert!(
+            !matches!(&response, Ok(r) if !r.is_empty()),
+            "{:?}",
+            response
+        );
+
+        // the header isn't expected from other peers
+        let addr = peer_addr_server(vec!["10.0.0.0/8".parse().unwrap()]);
+        let response = get(addr, b"").await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("\r\n\r\n127.0.0.1:"), "{}", response);
+    }
+}
@@ -0,0 +1,186 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use pektin_common::proto::op::Message;
+use pektin_common::proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+
+use crate::{PektinError, PektinResult};
+
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+/// The EDNS Client Subnet option (see RFC 7871).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSubnet {
+    /// The client's address, with all bits after `source_prefix` set to zero.
+    pub address: IpAddr,
+    pub source_prefix: u8,
+    pub scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// Extracts the client subnet option from the given query message.
+    ///
+    /// Returns `Ok(None)` if the message doesn't contain the option. If the option is malformed, a
+    /// response with [`ResponseCode::FormErr`] should be sent (see RFC 7871, section 7.1.1).
+    ///
+    /// [`ResponseCode::FormErr`]: pektin_common::proto::op::ResponseCode::FormErr
+    pub fn from_message(message: &Message) -> PektinResult<Option<Self>> {
+        let option = message
+            .extensions()
+            .as_ref()
+            .and_then(|edns| edns.option(EdnsCode::Subnet));
+        match option {
+            Some(EdnsOption::Unknown(_, data)) => Self::parse(data)
+                .map(Some)
+                .ok_or(PektinError::MalformedEdnsOption("client subnet")),
+            Some(_) => Err(PektinError::MalformedEdnsOption("client subnet")),
+            None => Ok(None),
+        }
+    }
+
+    /// Parses the option data of a client subnet option.
+    ///
+    /// Returns `None` if the data is malformed.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 4 {
+            return None;
+        }
+        let family = u16::from_be_bytes([data[0], data[1]]);
+        let source_prefix = data[2];
+        let scope_prefix = data[3];
+        let address_bytes = &data[4..];
+
+        // the scope prefix must be zero in queries
+        if scope_prefix != 0 || address_bytes.len() != (source_prefix as usize).div_ceil(8) {
+            return None;
+        }
+
+        let address = match family {
+            FAMILY_IPV4 if source_prefix <= 32 => {
+                let mut octets = [0; 4];
+                octets[..address_bytes.len()].copy_from_slice(address_bytes);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            FAMILY_IPV6 if source_prefix <= 128 => {
+                let mut octets = [0; 16];
+                octets[..address_bytes.len()].copy_from_slice(address_bytes);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+
+        // all bits after the source prefix must be zero
+        if truncate_address(address, source_prefix) != address {
+            return None;
+        }
+
+        Some(Self {
+            address,
+            source_prefix,
+            scope_prefix,
+        })
+    }
+
+    /// Converts this client subnet into an EDNS option that can be added to a response.
+    pub fn to_option(&self) -> EdnsOption {
+        let (family, octets) = match self.address {
+            IpAddr::V4(addr) => (FAMILY_IPV4, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (FAMILY_IPV6, addr.octets().to_vec()),
+        };
+        let address_len = (self.source_prefix as usize).div_ceil(8);
+
+        let mut data = Vec::with_capacity(4 + address_len);
+        data.extend_from_slice(&family.to_be_bytes());
+        data.push(self.source_prefix);
+        data.push(self.scope_prefix);
+        data.extend_from_slice(&octets[..address_len]);
+        EdnsOption::Unknown(EdnsCode::Subnet.into(), data)
+    }
+
+    /// Converts this client subnet into the EDNS option for the response to the query it came with.
+    ///
+    /// If the answer depends on the region of the client, it's only valid for exactly the subnet
+    /// the client told us about, since other clients in a larger subnet may be in other regions.
+    /// Otherwise, a scope prefix length of 0 tells resolvers that the answer is valid for all
+    /// clients (see RFC 7871, section 7.2.1).
+    pub fn response_option(mut self, region_specific: bool) -> EdnsOption {
+        self.scope_prefix = if region_specific {
+            self.source_prefix
+        } else {
+            0
+        };
+        self.to_option()
+    }
+}
+
+/// Sets all bits of the address after the first `prefix` bits to zero.
+fn truncate_address(address: IpAddr, prefix: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_truncated_addresses() {
+        let subnet = ClientSubnet::parse(&[0, 1, 24, 0, 192, 0, 2]).unwrap();
+        assert_eq!(subnet.address, IpAddr::from([192, 0, 2, 0]));
+        assert_eq!(subnet.source_prefix, 24);
+        assert_eq!(subnet.scope_prefix, 0);
+
+        let subnet = ClientSubnet::parse(&[0, 2, 32, 0, 0x20, 0x01, 0x0d, 0xb8]).unwrap();
+        assert_eq!(subnet.address, "2001:db8::".parse::<IpAddr>().unwrap());
+
+        let subnet = ClientSubnet::parse(&[0, 1, 0, 0]).unwrap();
+        assert_eq!(subnet.address, IpAddr::from([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn rejects_malformed_options() {
+        // too short
+        assert_eq!(ClientSubnet::parse(&[0, 1, 24]), None);
+        // scope prefix set in a query
+        assert_eq!(ClientSubnet::parse(&[0, 1, 24, 8, 192, 0, 2]), None);
+        // address longer than the source prefix
+        assert_eq!(ClientSubnet::parse(&[0, 1, 16, 0, 192, 0, 2]), None);
+        // bits set after the source prefix
+        assert_eq!(ClientSubnet::parse(&[0, 1, 23, 0, 192, 0, 3]), None);
+        // source prefix too long for the family
+        assert_eq!(ClientSubnet::parse(&[0, 1, 33, 0, 1, 2, 3, 4, 5]), None);
+        // unknown family
+        assert_eq!(ClientSubnet::parse(&[0, 3, 8, 0, 1]), None);
+    }
+
+    #[test]
+    fn option_round_trips() {
+        let mut subnet = ClientSubnet::parse(&[0, 1, 20, 0, 198, 51, 96]).unwrap();
+        subnet.scope_prefix = 16;
+        assert_eq!(
+            subnet.to_option(),
+            EdnsOption::Unknown(EdnsCode::Subnet.into(), vec![0, 1, 20, 16, 198, 51, 96])
+        );
+    }
+
+    #[test]
+    fn response_scope_depends_on_variants() {
+        let subnet = ClientSubnet::parse(&[0, 1, 24, 0, 192, 0, 2]).unwrap();
+        assert_eq!(
+            subnet.response_option(true),
+            EdnsOption::Unknown(EdnsCode::Subnet.into(), vec![0, 1, 24, 24, 192, 0, 2])
+        );
+        assert_eq!(
+            subnet.response_option(false),
+            EdnsOption::Unknown(EdnsCode::Subnet.into(), vec![0, 1, 24, 0, 192, 0, 2])
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use pektin_common::proto::op::Message;
+use pektin_common::proto::rr::dnssec::rdata::DNSSECRData;
+use pektin_common::proto::rr::rdata::opt::EdnsOption;
+use pektin_common::proto::rr::{RData, Record, RecordType};
+
+use crate::PektinError;
+
+/// The EDNS option code of Extended DNS Errors, which trust-dns doesn't know.
+const EDE_OPTION_CODE: u16 = 15;
+
+/// The Extended DNS Errors we use (see RFC 8914, section 4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendedError {
+    Other = 0,
+    DnssecBogus = 6,
+    SignatureExpired = 7,
+    SignatureNotYetValid = 8,
+    NsecMissing = 12,
+    Prohibited = 18,
+    NotAuthoritative = 20,
+    NoReachableAuthority = 22,
+    NetworkError = 23,
+}
+
+impl ExtendedError {
+    /// Chooses the extended error for a SERVFAIL response caused by the given error.
+    pub fn for_error(error: &anyhow::Error) -> Self {
+        error
+            .chain()
+            .find_map(|cause| match cause.downcast_ref::<PektinError>() {
+                Some(PektinError::DbUnavailable) => Some(Self::NoReachableAuthority),
+                Some(PektinError::DbError(_)) => Some(Self::NetworkError),
+                _ => None,
+            })
+            .unwrap_or(Self::Other)
+    }
+
+    /// Checks the validity period and the covered type of the given RRSIG records.
+    ///
+    /// Returns the extended error describing the first problem found, if any.
+    pub fn for_rrsigs(rrsigs: &[Record], type_covered: RecordType, now: u32) -> Option<Self> {
+        rrsigs.iter().find_map(|record| match record.data() {
+            Some(RData::DNSSEC(DNSSECRData::SIG(sig))) => {
+                if sig.type_covered() != type_covered {
+                    Some(Self::DnssecBogus)
+                } else if sig.sig_expiration() < now {
+                    Some(Self::SignatureExpired)
+                } else if sig.sig_inception() > now {
+                    Some(Self::SignatureNotYetValid)
+                } else {
+                    None
+                }
+            }
+            _ => Some(Self::DnssecBogus),
+        })
+    }
+
+    /// Adds this error to the response if it has EDNS.
+    ///
+    /// The extra text should only be given if it's fine to expose internal details to clients.
+    pub fn add_to(self, response: &mut Message, extra_text: Option<String>) {
+        if let Some(edns) = response.extensions_mut() {
+            let mut data = (self as u16).to_be_bytes().to_vec();
+            data.extend_from_slice(extra_text.unwrap_or_default().as_bytes());
+            edns.options_mut()
+                .insert(EdnsOption::Unknown(EDE_OPTION_CODE, data));
+        }
+    }
+}
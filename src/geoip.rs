@@ -0,0 +1,154 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+use maxminddb::geoip2::country::{Continent, Country};
+use maxminddb::Reader;
+use parking_lot::RwLock;
+use serde::Deserialize;
+
+use crate::{canonical_ip, PektinResult};
+
+/// The fields we're interested in from a GeoIP2/GeoLite2 Country, City or ASN database.
+#[derive(Deserialize)]
+struct GeoRecord<'a> {
+    #[serde(borrow)]
+    continent: Option<Continent<'a>>,
+    #[serde(borrow)]
+    country: Option<Country<'a>>,
+    autonomous_system_number: Option<u32>,
+}
+
+struct Database {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    reader: Reader<Vec<u8>>,
+}
+
+impl Database {
+    fn open(path: PathBuf) -> PektinResult<Self> {
+        let modified = std::fs::metadata(&path)?.modified().ok();
+        let reader = Reader::open_readfile(&path)?;
+        Ok(Self {
+            path,
+            modified,
+            reader,
+        })
+    }
+}
+
+/// Resolves client addresses to their country, continent and ASN using local MaxMind databases.
+///
+/// The databases are reloaded when the files change (see [`GeoIp::watch`]).
+#[derive(Default)]
+pub struct GeoIp {
+    databases: RwLock<Vec<Database>>,
+}
+
+impl GeoIp {
+    /// Opens the MaxMind databases at the given paths.
+    ///
+    /// Multiple databases can be used, e.g. a GeoLite2 Country and a GeoLite2 ASN database. If
+    /// several databases contain the same information, the first one wins.
+    pub fn open(paths: &[String]) -> PektinResult<Self> {
+        let databases = paths
+            .iter()
+            .map(|path| Database::open(path.into()))
+            .collect::<PektinResult<_>>()?;
+        Ok(Self {
+            databases: RwLock::new(databases),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.databases.read().is_empty()
+    }
+
+    /// Returns the RRset variants for the given address, ordered from most to least specific
+    /// (see [`crate::persistence::KeyOptions::variants`]).
+    ///
+    /// These are `asn:<number>`, `country:<iso code>` and `continent:<code>`, with the codes in
+    /// lowercase.
+    pub fn variants(&self, address: IpAddr) -> Vec<String> {
+        let address = canonical_ip(address);
+        let (mut asn, mut country, mut continent) = (None, None, None);
+        for database in self.databases.read().iter() {
+            // addresses that are not in the database are not an error for us
+            let record = match database.reader.lookup::<GeoRecord>(address) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            asn = asn.or(record.autonomous_system_number);
+            country = country.or_else(|| {
+                record
+                    .country
+                    .and_then(|c| c.iso_code)
+                    .map(str::to_ascii_lowercase)
+            });
+            continent = continent.or_else(|| {
+                record
+                    .continent
+                    .and_then(|c| c.code)
+                    .map(str::to_ascii_lowercase)
+            });
+        }
+
+        asn.map(|asn| format!("asn:{}", asn))
+            .into_iter()
+            .chain(country.map(|country| format!("country:{}", country)))
+            .chain(continent.map(|continent| format!("continent:{}", continent)))
+            .collect()
+    }
+
+    /// Checks the database files for changes every `interval` and reloads them if necessary.
+    ///
+    /// If a database can't be reloaded, the old version is kept.
+    pub async fn watch(&self, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+
+            let databases: Vec<_> = self
+                .databases
+                .read()
+                .iter()
+                .map(|db| (db.path.clone(), db.modified))
+                .collect();
+            // the file system is accessed on a blocking thread so that queries aren't held up
+            let changed = tokio::task::spawn_blocking(move || {
+                databases
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, (path, modified))| {
+                        let current = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+                        current.is_some() && current != *modified
+                    })
+                    .map(|(index, (path, _))| (index, path))
+                    .collect::<Vec<_>>()
+            })
+            .await;
+            let changed = match changed {
+                Ok(changed) => changed,
+                Err(e) => {
+                    warn!("Could not check GeoIP databases for changes: {}", e);
+                    continue;
+                }
+            };
+
+            for (index, path) in changed {
+                let open_path = path.clone();
+                match tokio::task::spawn_blocking(move || Database::open(open_path)).await {
+                    Ok(Ok(database)) => {
+                        info!("Reloaded GeoIP database {}", path.display());
+                        self.databases.write()[index] = database;
+                    }
+                    Ok(Err(e)) => {
+                        warn!("Could not reload GeoIP database {}: {}", path.display(), e)
+                    }
+                    Err(e) => warn!("Could not reload GeoIP database {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+}
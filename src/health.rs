@@ -0,0 +1,441 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Context;
+use futures_util::future::join_all;
+use log::warn;
+use parking_lot::RwLock;
+use pektin_common::deadpool_redis::redis::aio::Connection;
+use pektin_common::deadpool_redis::redis::{self, AsyncCommands};
+use pektin_common::deadpool_redis::Pool;
+use pektin_common::proto::rr::{RData, Record};
+use pektin_common::DbEntry;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::listeners;
+
+/// The health check of an RRset, stored as JSON at `<key>:HEALTH` for the RRset stored at `<key>`.
+///
+/// ```json
+/// { "probe": { "type": "http", "port": 80, "path": "/health" }, "backup": true }
+/// ```
+///
+/// The probe is run against the address of every A and AAAA record in the RRset. If `backup` is
+/// true, the RRset has a backup RRset stored as the variant `backup` (see
+/// [`KeyOptions::variants`](crate::persistence::KeyOptions::variants)) of the default RRset.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct HealthCheck {
+    probe: Probe,
+    #[serde(default)]
+    backup: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Probe {
+    /// Healthy if a TCP connection can be established.
+    Tcp { port: u16 },
+    /// Healthy if a GET request is answered with a 2xx or 3xx status code.
+    Http {
+        port: u16,
+        #[serde(default = "default_http_path")]
+        path: String,
+        /// The value of the Host header, defaults to the address.
+        host: Option<String>,
+    },
+    /// Healthy if any datagram is received in response to the payload.
+    Udp {
+        port: u16,
+        #[serde(default)]
+        payload: String,
+    },
+}
+
+/// The variant of the default RRset that is served instead of an unhealthy RRset.
+pub const BACKUP_VARIANT: &str = "backup";
+
+fn default_http_path() -> String {
+    "/".into()
+}
+
+/// The health of the members of an RRset, shared between servers as JSON at `<key>:HEALTHSTATE`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RrsetHealth {
+    pub unhealthy: HashSet<IpAddr>,
+    /// Whether the RRset has a backup RRset.
+    pub backup: bool,
+}
+
+impl RrsetHealth {
+    pub fn is_healthy(&self, record: &Record) -> bool {
+        match record.data() {
+            Some(RData::A(addr)) => !self.unhealthy.contains(&IpAddr::V4(*addr)),
+            Some(RData::AAAA(addr)) => !self.unhealthy.contains(&IpAddr::V6(*addr)),
+            _ => true,
+        }
+    }
+
+    pub fn all_unhealthy(&self, records: &[Record]) -> bool {
+        records.iter().all(|record| !self.is_healthy(record))
+    }
+}
+
+/// The health of all RRsets that have a health check, indexed by the db key of the RRset.
+///
+/// Only RRsets in the default db are checked.
+#[derive(Default)]
+pub struct Health {
+    rrsets: RwLock<HashMap<String, RrsetHealth>>,
+}
+
+impl Health {
+    /// Returns the health of the RRset stored at the given db key, if it has a health check.
+    pub fn get(&self, key: &str) -> Option<RrsetHealth> {
+        self.rrsets.read().get(key).cloned()
+    }
+
+    /// Runs the health checks every `interval` and publishes the results in the db.
+    ///
+    /// A probe fails if it doesn't succeed within `probe_timeout`.
+    pub async fn check(&self, db_pool: &Pool, interval: Duration, probe_timeout: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match run_health_checks(db_pool, interval, probe_timeout).await {
+                Ok(rrsets) => *self.rrsets.write() = rrsets,
+                Err(e) => warn!("Could not run health checks: {}", e),
+            }
+        }
+    }
+
+    /// Loads the health check results published by other servers every `interval`.
+    pub async fn follow(&self, db_pool: &Pool, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match load_health(db_pool).await {
+                Ok(rrsets) => *self.rrsets.write() = rrsets,
+                Err(e) => warn!("Could not load health check results: {}", e),
+            }
+        }
+    }
+}
+
+async fn run_health_checks(
+    db_pool: &Pool,
+    interval: Duration,
+    probe_timeout: Duration,
+) -> anyhow::Result<HashMap<String, RrsetHealth>> {
+    let mut con = db_pool
+        .get()
+        .await
+        .context("Could not get db connection from pool")?;
+    let keys: Vec<String> = con.keys("*:HEALTH").await?;
+    let mut rrsets = HashMap::with_capacity(keys.len());
+    for key in keys {
+        let rrset_key = key.strip_suffix(":HEALTH").unwrap_or(&key).to_string();
+        let (check, addresses) = match load_health_check(&mut con, &key, &rrset_key).await {
+            Ok(Some(c)) => c,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Invalid health check at {}: {}", key, e);
+                continue;
+            }
+        };
+
+        let results = join_all(
+            addresses
+                .iter()
+                .map(|addr| probe(&check.probe, *addr, probe_timeout)),
+        )
+        .await;
+        let health = RrsetHealth {
+            unhealthy: addresses
+                .into_iter()
+                .zip(results)
+                .filter(|(_, healthy)| !healthy)
+                .map(|(addr, _)| addr)
+                .collect(),
+            backup: check.backup,
+        };
+
+        // results expire if this server stops checking
+        let expiry = 3 * interval.as_secs().max(1) as usize;
+        con.set_ex::<_, _, ()>(
+            format!("{}:HEALTHSTATE", rrset_key),
+            serde_json::to_string(&health)?,
+            expiry,
+        )
+        .await?;
+        rrsets.insert(rrset_key, health);
+    }
+    Ok(rrsets)
+}
+
+/// Returns the health check stored at `key` and the addresses of the RRset it checks, or `None`
+/// if one of them doesn't exist.
+async fn load_health_check(
+    con: &mut Connection,
+    key: &str,
+    rrset_key: &str,
+) -> anyhow::Result<Option<(HealthCheck, Vec<IpAddr>)>> {
+    let (check, rrset): (Option<String>, Option<String>) = con.get(&[key, rrset_key]).await?;
+    let (check, rrset) = match (check, rrset) {
+        (Some(check), Some(rrset)) => (check, rrset),
+        _ => return Ok(None),
+    };
+    let check = serde_json::from_str(&check)?;
+    let addresses = DbEntry::deserialize_from_db(&base_key(rrset_key), &rrset)?
+        .convert()?
+        .iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::A(addr)) => Some(IpAddr::V4(*addr)),
+            Some(RData::AAAA(addr)) => Some(IpAddr::V6(*addr)),
+            _ => None,
+        })
+        .collect();
+    Ok(Some((check, addresses)))
+}
+
+async fn load_health(db_pool: &Pool) -> anyhow::Result<HashMap<String, RrsetHealth>> {
+    let mut con = db_pool
+        .get()
+        .await
+        .context("Could not get db connection from pool")?;
+    let keys: Vec<String> = con.keys("*:HEALTHSTATE").await?;
+    if keys.is_empty() {
+        return Ok(HashMap::new());
+    }
+    // GET instead of MGET would be used for a single key
+    let values: Vec<Option<String>> = redis::cmd("MGET").arg(&keys).query_async(&mut con).await?;
+    Ok(keys
+        .iter()
+        .zip(values)
+        .filter_map(|(key, value)| {
+            let rrset_key = key.strip_suffix(":HEALTHSTATE")?;
+            let health = serde_json::from_str(&value?).ok()?;
+            Some((rrset_key.to_string(), health))
+        })
+        .collect())
+}
+
+/// Removes the prefix and the variant from a db key, e.g. `view:example.com.:A@region:eu` becomes
+/// `example.com.:A` (see [`crate::persistence::KeyOptions`]).
+fn base_key(key: &str) -> String {
+    let without_variant = key.split('@').next().unwrap_or(key);
+    let mut parts = without_variant.rsplitn(3, ':');
+    match (parts.next(), parts.next()) {
+        (Some(rr_type), Some(name)) => format!("{}:{}", name, rr_type),
+        _ => without_variant.to_string(),
+    }
+}
+
+async fn probe(probe: &Probe, addr: IpAddr, probe_timeout: Duration) -> bool {
+    let result = match probe {
+        Probe::Tcp { port } => {
+            timeout(probe_timeout, probe_tcp(SocketAddr::new(addr, *port))).await
+        }
+        Probe::Http { port, path, host } => {
+            let host = host.clone().unwrap_or_else(|| addr.to_string());
+            timeout(
+                probe_timeout,
+                probe_http(SocketAddr::new(addr, *port), path, &host),
+            )
+            .await
+        }
+        Probe::Udp { port, payload } => {
+            timeout(
+                probe_timeout,
+                probe_udp(SocketAddr::new(addr, *port), payload),
+            )
+            .await
+        }
+    };
+    matches!(result, Ok(Ok(true)))
+}
+
+async fn probe_tcp(addr: SocketAddr) -> std::io::Result<bool> {
+    TcpStream::connect(addr).await.map(|_| true)
+}
+
+async fn probe_http(addr: SocketAddr, path: &str, host: &str) -> std::io::Result<bool> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes()).await?;
+    // we only need the status line, e.g. "HTTP/1.1 200 OK"
+    let mut buf = [0; 12];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf.starts_with(b"HTTP/") && matches!(buf[9], b'2' | b'3'))
+}
+
+async fn probe_udp(addr: SocketAddr, payload: &str) -> std::io::Result<bool> {
+    let socket = listeners::connect_udp(addr).await?;
+    socket.send(payload.as_bytes()).await?;
+    let mut buf = [0; 512];
+    socket.recv(&mut buf).await.map(|_| true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use super::*;
+
+    const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    /// Answers every HTTP request with the given status line.
+    async fn http_server(status_line: &'static str) -> u16 {
+        let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let response = format!("{}\r\nContent-Length: 0\r\n\r\n", status_line);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        port
+    }
+
+    /// Returns the port of a socket that was closed again.
+    async fn closed_port() -> u16 {
+        TcpListener::bind((LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[test]
+    fn strips_prefix_and_variant_from_keys() {
+        assert_eq!(base_key("example.com.:A"), "example.com.:A");
+        assert_eq!(base_key("example.com.:A@region:eu"), "example.com.:A");
+        assert_eq!(base_key("view:example.com.:AAAA"), "example.com.:AAAA");
+        assert_eq!(base_key("view:example.com.:A@backup"), "example.com.:A");
+        assert_eq!(base_key("no-type"), "no-type");
+    }
+
+    #[test]
+    fn parses_health_checks() {
+        let check: HealthCheck =
+            serde_json::from_str(r#"{ "probe": { "type": "http", "port": 80 }, "backup": true }"#)
+                .unwrap();
+        assert_eq!(
+            check,
+            HealthCheck {
+                probe: Probe::Http {
+                    port: 80,
+                    path: "/".into(),
+                    host: None
+                },
+                backup: true
+            }
+        );
+        let check: HealthCheck =
+            serde_json::from_str(r#"{ "probe": { "type": "tcp", "port": 22 } }"#).unwrap();
+        assert_eq!(check.probe, Probe::Tcp { port: 22 });
+        assert!(!check.backup);
+    }
+
+    #[tokio::test]
+    async fn probes_tcp() {
+        let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(probe(&Probe::Tcp { port }, LOCALHOST, PROBE_TIMEOUT).await);
+
+        let port = closed_port().await;
+        assert!(!probe(&Probe::Tcp { port }, LOCALHOST, PROBE_TIMEOUT).await);
+    }
+
+    #[tokio::test]
+    async fn probes_http() {
+        let http = |port| Probe::Http {
+            port,
+            path: "/health".into(),
+            host: None,
+        };
+        for (status_line, healthy) in [
+            ("HTTP/1.1 200 OK", true),
+            ("HTTP/1.1 204 No Content", true),
+            ("HTTP/1.0 301 Moved Permanently", true),
+            ("HTTP/1.1 404 Not Found", false),
+            ("HTTP/1.1 503 Service Unavailable", false),
+            ("SSH-2.0-OpenSSH_9.0", false),
+        ] {
+            let port = http_server(status_line).await;
+            assert_eq!(
+                probe(&http(port), LOCALHOST, PROBE_TIMEOUT).await,
+                healthy,
+                "{}",
+                status_line
+            );
+        }
+
+        let port = closed_port().await;
+        assert!(!probe(&http(port), LOCALHOST, PROBE_TIMEOUT).await);
+    }
+
+    #[tokio::test]
+    async fn sends_the_http_request_line_and_host() {
+        let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let check = Probe::Http {
+            port,
+            path: "/health".into(),
+            host: Some("www.example.com".into()),
+        };
+        assert!(probe(&check, LOCALHOST, PROBE_TIMEOUT).await);
+        let request = server.await.unwrap();
+        assert!(request.starts_with("GET /health HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nHost: www.example.com\r\n"));
+    }
+
+    #[tokio::test]
+    async fn probes_udp() {
+        let echo = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = echo.recv_from(&mut buf).await.unwrap();
+                assert_eq!(&buf[..len], b"ping");
+                echo.send_to(&buf[..len], peer).await.unwrap();
+            }
+        });
+        let udp = |port| Probe::Udp {
+            port,
+            payload: "ping".into(),
+        };
+        assert!(probe(&udp(port), LOCALHOST, PROBE_TIMEOUT).await);
+
+        // a socket that never answers
+        let silent = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+        let port = silent.local_addr().unwrap().port();
+        assert!(!probe(&udp(port), LOCALHOST, PROBE_TIMEOUT).await);
+    }
+}
@@ -1,16 +1,67 @@
+pub mod acl;
+pub mod alias;
+pub mod clients;
+pub mod config;
+pub mod connections;
+pub mod cookies;
+pub mod dname;
 pub mod doh;
+pub mod ecs;
+pub mod ede;
+pub mod geoip;
+pub mod health;
+pub mod listeners;
+pub mod metrics;
 pub mod persistence;
+pub mod policy;
+pub mod proxy;
+pub mod queue;
+pub mod ratelimit;
+pub mod regions;
+pub mod rrl;
+pub mod tsig;
+pub mod udp;
+pub mod views;
 
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use acl::Acls;
+use alias::{Alias, AliasResolver};
 use anyhow::{anyhow, bail, ensure, Context};
+use config::Config;
+use connections::TcpConnections;
+use cookies::{CookieSecrets, RequestCookie};
+use data_encoding::BASE32HEX_NOPAD;
+use ecs::ClientSubnet;
+use ede::ExtendedError;
 use futures_util::join;
-use log::{error, info};
+use geoip::GeoIp;
+use health::{Health, RrsetHealth, BACKUP_VARIANT};
+use log::{error, info, warn};
+use metrics::Metrics;
 use pektin_common::deadpool_redis::redis::aio::Connection;
 use pektin_common::deadpool_redis::Pool;
-use pektin_common::proto::op::{Edns, Message, MessageType, Query, ResponseCode};
-use pektin_common::proto::rr::{Name, RData, Record, RecordType};
-use pektin_common::{get_authoritative_zones, DbEntry, RrSet};
-use persistence::{get_rrset, get_rrsig, QueryResponse};
+use pektin_common::proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use pektin_common::proto::rr::dnssec::rdata::DNSSECRData;
+use pektin_common::proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use pektin_common::proto::rr::rdata::{HINFO, TXT};
+use pektin_common::proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use pektin_common::{DbEntry, RrSet};
+use persistence::{
+    get_alias, get_all_rrsigs, get_answer_policy, get_authoritative_zones, get_covering_nsec3_hash,
+    get_dname_rrsigs, get_dnames, get_rrset, get_rrset_types, get_rrsig, has_variants, KeyOptions,
+    QueryResponse,
+};
+use ratelimit::TokenBucket;
+use regions::RegionMap;
+use rrl::{ResponseClass, ResponseRateLimiter, RrlAction};
 use thiserror::Error;
+use tokio::sync::Semaphore;
+use tsig::{TsigKeys, VerifiedTsig};
+use views::{View, Views};
 
 #[derive(Debug, Error)]
 pub enum PektinError {
@@ -30,13 +81,117 @@ pub enum PektinError {
     InvalidDbData,
     #[error("requested db key had an unexpected type")]
     WickedDbValue,
+    #[error("malformed EDNS {0} option")]
+    MalformedEdnsOption(&'static str),
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("could not read GeoIP database: `{0}`")]
+    GeoIpError(#[from] maxminddb::MaxMindDBError),
+    #[error("could not get db connection from pool")]
+    DbUnavailable,
+    #[error("upstream resolver failed: {0}")]
+    UpstreamError(&'static str),
+    #[error("invalid PROXY protocol header: {0}")]
+    InvalidProxyHeader(&'static str),
     #[error("This is a bug, please report it: {0}")]
     Bug(&'static str),
 }
 pub type PektinResult<T> = Result<T, PektinError>;
 
+/// State that is shared between all requests.
+pub struct ServerState {
+    pub config: Config,
+    pub db_pool: Pool,
+    pub db_pool_dnssec: Pool,
+    pub regions: RegionMap,
+    pub geoip: GeoIp,
+    pub health: Health,
+    pub alias_resolver: AliasResolver,
+    pub tsig_keys: TsigKeys,
+    pub cookie_secrets: CookieSecrets,
+    pub metrics: Metrics,
+    /// Limits the FORMERR responses to malformed requests (see [`format_error_response`]).
+    pub format_error_limiter: TokenBucket,
+    pub rrl: ResponseRateLimiter,
+    pub views: Views,
+    pub acls: Acls,
+    pub tcp_connections: TcpConnections,
+    /// Limits the number of UDP and TCP queries processed at the same time.
+    pub in_flight_queries: Arc<Semaphore>,
+}
+
+/// The protocol a request was received over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Https,
+}
+
+/// Information about how a request was received.
+pub struct RequestInfo<'a> {
+    pub transport: Transport,
+    /// The address the request was received from, if known.
+    pub client_ip: Option<IpAddr>,
+    /// The request exactly as it was received, needed to verify TSIG signatures.
+    pub raw_message: &'a [u8],
+}
+
+/// Converts IPv4-mapped IPv6 addresses (as received on dual-stack sockets) to IPv4 addresses.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Builds a FORMERR response to a request that couldn't be parsed, echoing only its ID and opcode
+/// (see RFC 1035, section 4.1.1).
+///
+/// Returns `None` if not even the header of the request could be read, if the request is a
+/// response itself, or if too many FORMERR responses were sent recently.
+pub fn format_error_response(raw_message: &[u8], state: &ServerState) -> Option<Vec<u8>> {
+    state
+        .metrics
+        .malformed_requests
+        .fetch_add(1, Ordering::Relaxed);
+    // the header is 12 bytes long, and the QR bit is the highest bit of the third byte
+    if raw_message.len() < 12 || raw_message[2] & 0x80 != 0 {
+        return None;
+    }
+    // the response is as large as the request at most, but spoofed requests could still be used
+    // to make us send lots of packets to a victim
+    if !state.format_error_limiter.try_acquire() {
+        state
+            .metrics
+            .suppressed_format_errors
+            .fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+
+    let mut response = vec![0; 12];
+    response[..2].copy_from_slice(&raw_message[..2]);
+    // the QR bit and the opcode of the request
+    response[2] = 0x80 | (raw_message[2] & 0x78);
+    response[3] = ResponseCode::FormErr.low();
+    Some(response)
+}
+
 /// Takes the given query message, processes it, and returns an appropriate response message.
-pub async fn process_request(mut message: Message, db_pool: Pool, db_pool_dnssec: Pool) -> Message {
+///
+/// Returns `None` if the message must not be answered, i.e. if it is a response itself or if
+/// response rate limiting drops the response.
+pub async fn process_request(
+    mut message: Message,
+    request_info: RequestInfo<'_>,
+    state: &ServerState,
+) -> Option<Message> {
+    // answering responses could lead to loops between servers
+    if message.message_type() == MessageType::Response {
+        info!("Ignoring received response message");
+        return None;
+    }
+
     let mut response = Message::new();
     response.set_id(message.id());
     response.set_message_type(MessageType::Response);
@@ -46,94 +201,472 @@ pub async fn process_request(mut message: Message, db_pool: Pool, db_pool_dnssec
     response.set_authoritative(true);
 
     // only add EDNS to the response if it's present in the query message
-    if message.extensions().is_some() {
+    if let Some(request_edns) = message.extensions() {
         let mut edns = Edns::new();
         // TODO: think about payload size (see https://www.rfc-editor.org/rfc/rfc6891#section-6.2.5)
         edns.set_max_payload(4096);
+        // the client asks which server answered (see RFC 5001)
+        if state.config.use_nsid && request_edns.option(EdnsCode::NSID).is_some() {
+            edns.options_mut().insert(EdnsOption::Unknown(
+                EdnsCode::NSID.into(),
+                state.config.server_id.as_bytes().to_vec(),
+            ));
+        }
+        // tell the client how long it may keep the connection open (see RFC 7828), which must
+        // not be done over UDP
+        if request_info.transport == Transport::Tcp
+            && request_edns.option(EdnsCode::Keepalive).is_some()
+        {
+            // the timeout is given in units of 100 milliseconds
+            let timeout = state
+                .config
+                .tcp_timeout_seconds
+                .saturating_mul(10)
+                .min(u16::MAX as u64) as u16;
+            edns.options_mut().insert(EdnsOption::Unknown(
+                EdnsCode::Keepalive.into(),
+                timeout.to_be_bytes().to_vec(),
+            ));
+        }
         response.set_edns(edns);
     }
 
-    if let Err(e) = process_request_internal(&mut response, &message, db_pool, db_pool_dnssec).await
-    {
-        error!("ServFail: {}", e);
-        response.set_response_code(ResponseCode::ServFail);
-        // drop any entries that might have already been added to the response
-        response.answers_mut().clear();
-        response.name_servers_mut().clear();
-        response.additionals_mut().clear();
+    if let Err(response_code) = validate_request(&message) {
+        info!(
+            "Received invalid request, responding with {}",
+            response_code
+        );
+        response.set_response_code(response_code);
+        response.add_queries(message.take_queries());
+        return Some(response);
+    }
+
+    let request_tsig = match state.tsig_keys.verify(&message, request_info.raw_message) {
+        Ok(tsig) => tsig,
+        Err(failure) => {
+            info!("Received request with invalid TSIG signature");
+            response.set_response_code(ResponseCode::NotAuth);
+            response.add_queries(message.take_queries());
+            if let Err(e) = state.tsig_keys.add_failure(&mut response, *failure) {
+                error!("Could not add TSIG error to response: {}", e);
+            }
+            return Some(response);
+        }
+    };
+
+    let mut valid_cookie = false;
+    let edns_options = ClientSubnet::from_message(&message)
+        .and_then(|subnet| Ok((subnet, RequestCookie::from_message(&message)?)));
+    match edns_options {
+        Ok((client_subnet, cookie)) => {
+            valid_cookie = add_server_cookie(
+                &mut response,
+                cookie.as_ref(),
+                request_info.client_ip,
+                state,
+            );
+            if cookie.is_some()
+                && !valid_cookie
+                && state.config.require_cookies
+                && request_info.transport == Transport::Udp
+            {
+                // the client has to retry with the server cookie we just sent it
+                response.set_response_code(ResponseCode::BADCOOKIE);
+            } else if !state.acls.allows(
+                request_info.transport,
+                request_info.client_ip,
+                request_tsig.as_ref().map(|tsig| &tsig.key_name),
+                message.queries().first().map(|query| query.name()),
+            ) {
+                refuse_prohibited(&mut response);
+            } else {
+                answer_request(
+                    &mut response,
+                    &message,
+                    client_subnet,
+                    &request_info,
+                    request_tsig.as_ref(),
+                    state,
+                )
+                .await;
+            }
+        }
+        Err(e) => {
+            info!("Received invalid message: {}", e);
+            response.set_response_code(ResponseCode::FormErr);
+        }
     }
 
     // echo back the query section in the response
-    response.add_queries(message.take_queries().into_iter());
+    response.add_queries(message.take_queries());
+
+    // a valid server cookie proves that the client's address isn't spoofed (see RFC 7873,
+    // section 5.2.3), so such responses can't be used for reflection attacks
+    if let (Transport::Udp, Some(client_ip), false) =
+        (request_info.transport, request_info.client_ip, valid_cookie)
+    {
+        match state
+            .rrl
+            .check(client_ip, ResponseClass::of(&response), &state.metrics)
+        {
+            RrlAction::Send => {}
+            RrlAction::Drop => return None,
+            RrlAction::Slip => {
+                response.take_answers();
+                response.take_name_servers();
+                response.take_additionals();
+                response.set_truncated(true);
+            }
+        }
+    }
+
+    // signed requests get signed responses (see RFC 8945, section 5.3)
+    if let Some(tsig) = &request_tsig {
+        if let Err(e) = state.tsig_keys.sign(&mut response, tsig) {
+            error!("Could not sign response: {}", e);
+        }
+    }
+
+    Some(response)
+}
+
+/// Answers a request with valid EDNS options.
+async fn answer_request(
+    response: &mut Message,
+    message: &Message,
+    client_subnet: Option<ClientSubnet>,
+    request_info: &RequestInfo<'_>,
+    request_tsig: Option<&VerifiedTsig>,
+    state: &ServerState,
+) {
+    let variants = client_variants(request_info.client_ip, client_subnet, state);
+
+    let view = state.views.select(
+        request_info.client_ip,
+        request_tsig.map(|tsig| &tsig.key_name),
+    );
+    let options = KeyOptions {
+        prefix: view.key_prefix.clone(),
+        variants,
+    };
+    let region_specific = match process_request_internal(
+        response,
+        message,
+        request_info,
+        request_tsig.map(|tsig| &tsig.key_name),
+        view,
+        &options,
+        state,
+    )
+    .await
+    {
+        Ok(region_specific) => region_specific,
+        Err(e) => {
+            error!("ServFail: {}", e);
+            response.set_response_code(ResponseCode::ServFail);
+            // drop any entries that might have already been added to the response
+            response.answers_mut().clear();
+            response.name_servers_mut().clear();
+            response.additionals_mut().clear();
+            let extra_text = state.config.ede_extra_text.then(|| format!("{:#}", e));
+            ExtendedError::for_error(&e).add_to(response, extra_text);
+            false
+        }
+    };
+
+    // echo back the client subnet option with the scope our answer is valid for
+    if let (Some(subnet), Some(edns)) = (client_subnet, response.extensions_mut()) {
+        edns.options_mut()
+            .insert(subnet.response_option(region_specific));
+    }
+}
+
+/// Adds a COOKIE option with a fresh server cookie to the response if the request contained a
+/// COOKIE option.
+///
+/// Returns whether the request contained a valid server cookie.
+fn add_server_cookie(
+    response: &mut Message,
+    cookie: Option<&RequestCookie>,
+    client_ip: Option<IpAddr>,
+    state: &ServerState,
+) -> bool {
+    let (cookie, client_ip) = match (cookie, client_ip) {
+        (Some(c), Some(ip)) => (c, ip),
+        _ => return false,
+    };
+    let now = unix_time();
+    let valid = state.cookie_secrets.verify(cookie, client_ip, now);
+    if let Some(edns) = response.extensions_mut() {
+        edns.options_mut()
+            .insert(state.cookie_secrets.response_option(cookie, client_ip, now));
+    }
+    valid
+}
 
-    response
+/// Returns the current UNIX time in seconds, as used in DNS.
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// Determines the variants of the RRsets that should be served to the client, based on its region
+/// and its GeoIP information (see [`KeyOptions::variants`]).
+///
+/// These are determined from the EDNS client subnet if present and from the client's address
+/// otherwise.
+fn client_variants(
+    client_ip: Option<IpAddr>,
+    client_subnet: Option<ClientSubnet>,
+    state: &ServerState,
+) -> Vec<String> {
+    if state.regions.is_empty() && state.geoip.is_empty() {
+        return vec![];
+    }
+
+    // a source prefix length of 0 means the client doesn't want its subnet to be used
+    let address = match client_subnet {
+        Some(subnet) if subnet.source_prefix > 0 => Some(subnet.address),
+        _ => client_ip,
+    };
+    let mut variants = vec![];
+    if let Some(addr) = address {
+        // explicitly configured regions take precedence over GeoIP information
+        if let Some(region) = state.regions.region_of(addr) {
+            variants.push(format!("region:{}", region));
+        }
+        variants.extend(state.geoip.variants(addr));
+    }
+    variants
 }
 
 /// Does most of the work for process_request(), but is allowed to return an error.
 ///
-/// This error is logged in process_request(), and then a SERVFAIL response is returned.
+/// This error is logged in process_request(), and then a SERVFAIL response is returned. Otherwise,
+/// returns whether the answer depends on the region of the client, i.e. whether the RRset has
+/// variants (see [`KeyOptions::variants`]), even if the client got the default RRset.
 async fn process_request_internal(
     response: &mut Message,
     message: &Message,
-    db_pool: Pool,
-    db_pool_dnssec: Pool,
-) -> anyhow::Result<()> {
-    if !validate_query_message(message) {
-        info!("Received invalid message");
-        response.set_response_code(ResponseCode::FormErr);
-        return Ok(());
+    request_info: &RequestInfo<'_>,
+    tsig_key: Option<&Name>,
+    view: &View,
+    options: &KeyOptions,
+    state: &ServerState,
+) -> anyhow::Result<bool> {
+    // validate_request() checks that there is exactly one query
+    let query = message.queries().first().ok_or_else(|| {
+        anyhow!("no query in message - validate_request() should have prevented this")
+    })?;
+
+    // server identification queries don't need any data from the db
+    if query.query_class() == DNSClass::CH {
+        answer_chaos_query(response, query, state);
+        return Ok(false);
+    }
+
+    let do_flag = message
+        .extensions()
+        .as_ref()
+        .map(|edns| edns.dnssec_ok())
+        .unwrap_or(false);
+
+    // minimal responses to ANY queries don't need any data from the db, so that ANY queries can't
+    // be used to make us look up all RRsets of a name
+    let all_rrsets = request_info.transport != Transport::Udp && state.config.any_over_tcp;
+    if query.query_type() == RecordType::ANY && !all_rrsets && !do_flag {
+        add_any_hinfo(response, query);
+        return Ok(false);
     }
 
+    let (db_pool, db_pool_dnssec) = match &view.db_pools {
+        Some((pool, dnssec_pool)) => (pool, dnssec_pool),
+        None => (&state.db_pool, &state.db_pool_dnssec),
+    };
     let (mut con, mut dnssec_con) = match join!(db_pool.get(), db_pool_dnssec.get()) {
         (Ok(c), Ok(db_c)) => (c, db_c),
         _ => {
-            bail!("could not get db and dnssec db connection from pool");
+            bail!(PektinError::DbUnavailable);
         }
     };
 
-    // TODO: check how to handle wildcards according to the relevant RFCs
-    // (does a.b.example.com match *.example.com?)
+    // the zone ACLs also apply to the names the query is redirected to, which may be in another
+    // zone
+    let allowed = |name: &Name| {
+        state.acls.allows(
+            request_info.transport,
+            request_info.client_ip,
+            tsig_key,
+            Some(name),
+        )
+    };
 
-    // validate_query_message() checks that there is exactly one query
-    let query = message.queries().get(0).ok_or_else(|| {
-        anyhow!("no query in message - validate_query_message() should have prevented this")
-    })?;
+    let query = match follow_dnames(
+        response,
+        query,
+        do_flag,
+        options,
+        &allowed,
+        &mut con,
+        &mut dnssec_con,
+    )
+    .await?
+    {
+        Some(q) => q,
+        None => return Ok(false),
+    };
+    let query = &query;
+
+    if dname::is_dname_type(query.query_type())
+        && add_dname_answer(response, query, do_flag, options, &mut con, &mut dnssec_con).await?
+    {
+        return Ok(false);
+    }
+
+    if query.query_type() == RecordType::ANY {
+        if let Some(variant_served) = add_any_answers(
+            response,
+            query,
+            all_rrsets,
+            do_flag,
+            options,
+            &mut con,
+            &mut dnssec_con,
+        )
+        .await?
+        {
+            return Ok(variant_served);
+        }
+    }
 
     // try to find a matching answer (wildcard allowed).
     // keep track if we added an answer into the message (response.answer_count() doesn't
     // automatically update)
-    let answers = find_answers(query, get_rrset, &mut con).await?;
-    let answer_stored = !answers.is_empty();
-    response.add_answers(answers);
+    let mut answers = match query.query_type() {
+        RecordType::RRSIG => {
+            let records = get_all_rrsigs(&mut dnssec_con, query.name(), options)
+                .await
+                .context("Could not get RRSIG records")?
+                .into_iter()
+                .map(|entry| entry.convert())
+                .collect::<Result<Vec<_>, _>>()?;
+            Answers {
+                records: records.into_iter().flatten().collect(),
+                ..Default::default()
+            }
+        }
+        rr_type if is_dnssec_type(rr_type) => {
+            let answers = find_answers(query, get_rrset, options, &mut dnssec_con).await?;
+            // fall back to records that were added manually
+            if answers.records.is_empty() {
+                find_answers(query, get_rrset, options, &mut con).await?
+            } else {
+                answers
+            }
+        }
+        _ => find_answers(query, get_rrset, options, &mut con).await?,
+    };
+    if answers.records.is_empty() && matches!(query.query_type(), RecordType::A | RecordType::AAAA)
+    {
+        if let Some(alias) = get_alias(&mut con, query.name(), options)
+            .await
+            .context("Could not get ALIAS record")?
+        {
+            match resolve_alias(query, &alias, options, state, &allowed, &mut con).await? {
+                Some(records) => answers.records = records,
+                None => {
+                    refuse_prohibited(response);
+                    return Ok(false);
+                }
+            }
+        }
+    }
+    let owner = if answers.wildcard {
+        query.name().clone().into_wildcard()
+    } else {
+        query.name().clone()
+    };
+    let rrset_key = options.rrset_key(&owner, query.query_type(), answers.variant.as_deref());
+    let health = state.health.get(&rrset_key);
+    // the RRSIG records are needed for DO queries and to know whether records may be left out of
+    // the RRset by the health checks or the answer policy, since they only match the complete
+    // RRset
+    let mut rrsigs = if do_flag || health.is_some() || answers.records.len() > 1 {
+        find_rrsigs(query, &answers, options, &mut dnssec_con).await?
+    } else {
+        Answers::default()
+    };
+    if let Some(health) = &health {
+        let variant = answers.variant.clone();
+        let signed = !rrsigs.records.is_empty();
+        answers = apply_health(answers, health, query, options, signed, &mut con).await?;
+        // the backup RRset has its own RRSIG records
+        if answers.variant != variant {
+            rrsigs = find_rrsigs(query, &answers, options, &mut dnssec_con).await?;
+        }
+    }
+    if answers.records.len() > 1 {
+        let policy = get_answer_policy(
+            &mut con,
+            &owner,
+            query.query_type(),
+            answers.variant.as_deref(),
+            options,
+        )
+        .await
+        .context("Could not get answer policy")?;
+        let is_healthy = |record: &Record| health.as_ref().is_none_or(|h| h.is_healthy(record));
+        let signed = !rrsigs.records.is_empty();
+        policy.apply(&mut answers.records, is_healthy, signed);
+    }
+    let answer_stored = !answers.records.is_empty();
+    let wildcard_match = answers.wildcard;
+    let variant_served = answers.variant.is_some();
+    response.add_answers(answers.records);
 
-    // if we found a matching answer and the DO flag is set, try to get the matching RRSIG entries
-    let do_flag = message
-        .extensions()
-        .as_ref()
-        .map(|edns| edns.dnssec_ok())
-        .unwrap_or(false);
+    // clients in other regions may get another answer, even if this client got the default RRset
+    let geo_routing = !(state.regions.is_empty() && state.geoip.is_empty());
+    let region_specific = variant_served
+        || (geo_routing
+            && has_variants(&mut con, &owner, query.query_type(), options)
+                .await
+                .context("Could not get RRset variants")?);
+
+    // if we found a matching answer and the DO flag is set, add the matching RRSIG entries
     if answer_stored && do_flag {
-        let answers = find_answers(query, get_rrsig, &mut dnssec_con).await?;
-        response.add_answers(answers);
+        if let Some(e) = ExtendedError::for_rrsigs(&rrsigs.records, query.query_type(), unix_time())
+        {
+            warn!(
+                "Invalid RRSIG records for {} {}",
+                query.name(),
+                query.query_type()
+            );
+            e.add_to(response, None);
+        }
+        let signed = !rrsigs.records.is_empty();
+        response.add_answers(rrsigs.records);
+
+        // a wildcard answer is only valid if we also prove that the exact name doesn't exist
+        // (see RFC 5155, section 7.2.6); there's nothing to prove for unsigned zones
+        if wildcard_match && signed {
+            let auth_zone = find_authoritative_zone(query.name(), options, &mut con)
+                .await?
+                .ok_or_else(|| anyhow!("Wildcard match for a name outside of our zones"))?;
+            // the closest encloser is the owner of the wildcard record without the * label, so
+            // the next closer name has one more label than the closest encloser
+            let closest_encloser_labels = query.name().base_name().num_labels() as usize;
+            let next_closer = query.name().trim_to(closest_encloser_labels + 1);
+            add_covering_nsec3(response, &next_closer, &auth_zone, options, &mut dnssec_con)
+                .await?;
+        }
     }
 
     // we haven't found a matching answer, therefore try to find the SOA record for the query's zone
     // and respond with that instead
     if !answer_stored {
-        let mut authoritative_zones = get_authoritative_zones(&mut con)
-            .await
-            .context("Could not get authoritative zones")?
-            .into_iter()
-            .map(|zone| {
-                Name::from_utf8(zone).map_err(|_| anyhow!("Name in db is not a valid DNS name"))
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-        // the - makes it sort the zones with the most labels first
-        authoritative_zones.sort_by_key(|zone| -(zone.num_labels() as i16));
-
-        let authoritative_zone = authoritative_zones
-            .into_iter()
-            .find(|zone| zone.zone_of(query.name()));
+        let authoritative_zone = find_authoritative_zone(query.name(), options, &mut con).await?;
 
         if let Some(auth_zone) = authoritative_zone {
             add_soa_and_nsec3(
@@ -141,6 +674,7 @@ async fn process_request_internal(
                 query,
                 auth_zone,
                 do_flag,
+                options,
                 &mut con,
                 &mut dnssec_con,
             )
@@ -148,40 +682,500 @@ async fn process_request_internal(
         } else {
             // the query was for a zone we're not authoritative for
             response.set_response_code(ResponseCode::Refused);
+            ExtendedError::NotAuthoritative.add_to(response, None);
         }
     }
 
-    Ok(())
+    Ok(region_specific)
 }
 
-/// Checks that the given query is valid.
+/// Answers a CHAOS class query for the identity or version of the server.
 ///
-/// Returns true, if it valid, else false. A response to an invalid query should have a response
-/// code of [`ResponseCode::FormErr`].
+/// Only the names configured in `CHAOS_NAMES` are answered, all other queries are refused.
+fn answer_chaos_query(response: &mut Message, query: &Query, state: &ServerState) {
+    let name = query.name().to_lowercase().to_ascii();
+    if !state.config.chaos_names.contains(&name) {
+        response.set_response_code(ResponseCode::Refused);
+        return;
+    }
+    if !matches!(query.query_type(), RecordType::TXT | RecordType::ANY) {
+        return;
+    }
+
+    let text = match name.as_str() {
+        "version.bind." | "version.server." => {
+            format!("pektin-server {}", env!("CARGO_PKG_VERSION"))
+        }
+        _ => state.config.server_id.clone(),
+    };
+    let mut record = Record::from_rdata(query.name().clone(), 0, RData::TXT(TXT::new(vec![text])));
+    record.set_dns_class(DNSClass::CH);
+    response.add_answer(record);
+}
+
+/// The TTL of the HINFO record synthesized for ANY queries.
+const ANY_HINFO_TTL: u32 = 3600;
+
+/// Answers an ANY query with the synthesized HINFO record of minimal responses (see RFC 8482).
+fn add_any_hinfo(response: &mut Message, query: &Query) {
+    let hinfo = HINFO::new("RFC8482".into(), "".into());
+    response.add_answer(Record::from_rdata(
+        query.name().clone(),
+        ANY_HINFO_TTL,
+        RData::HINFO(hinfo),
+    ));
+}
+
+/// Answers an ANY query that isn't answered with [`add_any_hinfo`].
 ///
-/// Currently, this only checks that it contains exactly one entry in the question section.
-fn validate_query_message(query: &Message) -> bool {
-    query.queries().len() == 1
+/// If `all_rrsets` is true, which is configurable for TCP and HTTPS, all RRsets at the name are
+/// returned. Otherwise, the DO flag is set and one of the RRsets at the name is returned (since we
+/// can't sign the HINFO record). Returns `None` if there are no RRsets at the name that can be
+/// served to the client, and otherwise whether any of the returned RRsets is a variant.
+async fn add_any_answers(
+    response: &mut Message,
+    query: &Query,
+    all_rrsets: bool,
+    do_flag: bool,
+    options: &KeyOptions,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> anyhow::Result<Option<bool>> {
+    let rr_types = get_rrset_types(con, query.name(), options)
+        .await
+        .context("Could not get RRset types")?;
+
+    // some of the types may only exist as variants that aren't served to this client
+    let mut answered = false;
+    let mut variant_served = false;
+    for rr_type in rr_types {
+        let typed_query = Query::query(query.name().clone(), rr_type);
+        let answers = find_answers(&typed_query, get_rrset, options, con).await?;
+        if answers.records.is_empty() {
+            continue;
+        }
+        answered = true;
+        variant_served |= answers.variant.is_some();
+        // the RRSIG records have to belong to the same variant as the RRset
+        let rrsig_options = KeyOptions {
+            prefix: options.prefix.clone(),
+            variants: answers.variant.into_iter().collect(),
+        };
+        response.add_answers(answers.records);
+        if do_flag {
+            let rrsigs = find_answers(&typed_query, get_rrsig, &rrsig_options, dnssec_con).await?;
+            response.add_answers(rrsigs.records);
+        }
+        if !all_rrsets {
+            break;
+        }
+    }
+    Ok(answered.then_some(variant_served))
+}
+
+/// The maximum number of DNAME records we follow for a single query.
+const MAX_DNAME_CHAIN: usize = 8;
+
+/// Follows the DNAME records at ancestors of the queried name (see RFC 6672).
+///
+/// For every DNAME record, adds it (and its RRSIG records if `do_flag` is set) and the
+/// synthesized CNAME record to the answer section. Returns the query for the name the query is
+/// redirected to, or `None` if the response is complete because that name isn't in our zones or
+/// because `allowed` (which checks the zone ACLs) doesn't allow the client to query it.
+async fn follow_dnames(
+    response: &mut Message,
+    query: &Query,
+    do_flag: bool,
+    options: &KeyOptions,
+    allowed: impl Fn(&Name) -> bool,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> anyhow::Result<Option<Query>> {
+    let mut name = query.name().clone();
+    for _ in 0..MAX_DNAME_CHAIN {
+        // a DNAME record doesn't apply to its own name; starting at the root makes sure that DNAME
+        // records below another DNAME record are ignored
+        let ancestors: Vec<_> = (1..name.num_labels())
+            .map(|labels| name.trim_to(labels as usize))
+            .collect();
+        let dnames = get_dnames(con, &ancestors, options)
+            .await
+            .context("Could not get DNAME records")?;
+        let (owner, dname) = match ancestors
+            .into_iter()
+            .zip(dnames)
+            .find_map(|(ancestor, dname)| dname.map(|d| (ancestor, d)))
+        {
+            Some(d) => d,
+            None => break,
+        };
+
+        response.add_answer(dname.to_record(owner.clone())?);
+        if do_flag {
+            response.add_answers(get_dname_rrsigs(dnssec_con, &owner, options).await?);
+        }
+        let redirected = match dname.redirect(&name, &owner)? {
+            Some(r) => r,
+            None => {
+                // the redirected name would be longer than 255 bytes
+                response.set_response_code(ResponseCode::YXDomain);
+                return Ok(None);
+            }
+        };
+        response.add_answer(Record::from_rdata(
+            name,
+            dname.ttl,
+            RData::CNAME(redirected.clone()),
+        ));
+
+        if find_authoritative_zone(&redirected, options, con)
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        if !allowed(&redirected) {
+            refuse_prohibited(response);
+            return Ok(None);
+        }
+        name = redirected;
+    }
+
+    let mut redirected_query = query.clone();
+    redirected_query.set_name(name);
+    Ok(Some(redirected_query))
+}
+
+/// Answers a query for the DNAME record of the queried name, which isn't found like other RRsets
+/// since trust-dns doesn't know its type.
+///
+/// Returns whether the name has a DNAME record.
+async fn add_dname_answer(
+    response: &mut Message,
+    query: &Query,
+    do_flag: bool,
+    options: &KeyOptions,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> anyhow::Result<bool> {
+    let dname = get_dnames(con, std::slice::from_ref(query.name()), options)
+        .await
+        .context("Could not get DNAME record")?
+        .pop()
+        .flatten();
+    let dname = match dname {
+        Some(d) => d,
+        None => return Ok(false),
+    };
+    response.add_answer(dname.to_record(query.name().clone())?);
+    if do_flag {
+        response.add_answers(get_dname_rrsigs(dnssec_con, query.name(), options).await?);
+    }
+    Ok(true)
+}
+
+/// Refuses a query that the ACLs don't allow, dropping the answers that were already added.
+fn refuse_prohibited(response: &mut Message) {
+    info!("Refusing query prohibited by ACL");
+    response.answers_mut().clear();
+    response.set_response_code(ResponseCode::Refused);
+    ExtendedError::Prohibited.add_to(response, None);
+}
+
+/// Whether records of the given type are published by the signer in the DNSSEC db.
+fn is_dnssec_type(rr_type: RecordType) -> bool {
+    matches!(
+        rr_type,
+        RecordType::DS | RecordType::NSEC3 | RecordType::NSEC3PARAM
+    )
+}
+
+/// The type codes of the obsolete MAILB and MAILA query types (see RFC 1035, section 3.2.3).
+const MAILB_TYPE: u16 = 253;
+const MAILA_TYPE: u16 = 254;
+
+/// Checks that the given request is a query we can answer.
+///
+/// If it isn't, returns the response code that the response to the request should have.
+fn validate_request(message: &Message) -> Result<(), ResponseCode> {
+    if message.op_code() != OpCode::Query {
+        return Err(ResponseCode::NotImp);
+    }
+    if let Some(edns) = message.extensions() {
+        if edns.version() > 0 {
+            return Err(ResponseCode::BADVERS);
+        }
+    }
+    // trust-dns only takes the OPT record from the additional section and rejects messages with
+    // more than one OPT record, so any remaining OPT record is misplaced
+    let misplaced_opt = message
+        .answers()
+        .iter()
+        .chain(message.name_servers())
+        .chain(message.additionals())
+        .any(|record| record.rr_type() == RecordType::OPT);
+    if misplaced_opt || message.queries().len() != 1 {
+        return Err(ResponseCode::FormErr);
+    }
+
+    let query = &message.queries()[0];
+    match query.query_class() {
+        DNSClass::IN | DNSClass::CH => {}
+        DNSClass::NONE | DNSClass::ANY => return Err(ResponseCode::NotImp),
+        _ => return Err(ResponseCode::Refused),
+    }
+    match query.query_type() {
+        RecordType::OPT => Err(ResponseCode::FormErr),
+        // we don't do zone transfers
+        RecordType::AXFR | RecordType::IXFR => Err(ResponseCode::Refused),
+        rr_type if matches!(u16::from(rr_type), MAILB_TYPE | MAILA_TYPE) => {
+            Err(ResponseCode::NotImp)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Finds the most specific zone we're authoritative for that contains the given name.
+async fn find_authoritative_zone(
+    name: &Name,
+    options: &KeyOptions,
+    con: &mut Connection,
+) -> anyhow::Result<Option<Name>> {
+    let mut authoritative_zones = get_authoritative_zones(con, options)
+        .await
+        .context("Could not get authoritative zones")?
+        .into_iter()
+        .map(|zone| {
+            Name::from_utf8(zone).map_err(|_| anyhow!("Name in db is not a valid DNS name"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // the - makes it sort the zones with the most labels first
+    authoritative_zones.sort_by_key(|zone| -(zone.num_labels() as i16));
+
+    Ok(authoritative_zones
+        .into_iter()
+        .find(|zone| zone.zone_of(name)))
+}
+
+/// The records found by [`find_answers`].
+#[derive(Debug, Default)]
+struct Answers {
+    records: Vec<Record>,
+    /// Whether the records were synthesized from a wildcard record.
+    wildcard: bool,
+    /// The variant of the RRset the records were taken from (see [`KeyOptions::variants`]).
+    variant: Option<String>,
 }
 
 /// Tries to find answers to the given query with the provided function.
-async fn find_answers<'c, 'n, O, F>(
+///
+/// If the answers were synthesized from a wildcard record, the owner name of all records is
+/// rewritten to the queried name. RRSIG records keep their original label count, which is what
+/// allows validators to detect the wildcard expansion.
+async fn find_answers<'c, 'n, 'v, O, F>(
     query: &'n Query,
     get_fn: F,
+    options: &'v KeyOptions,
     con: &'c mut Connection,
-) -> PektinResult<Vec<Record>>
+) -> PektinResult<Answers>
 where
-    O: futures_util::Future<Output = PektinResult<QueryResponse>>,
-    F: Fn(&'c mut Connection, &'n Name, RecordType) -> O,
+    O: futures_util::Future<Output = PektinResult<(QueryResponse, Option<String>)>>,
+    F: Fn(&'c mut Connection, &'n Name, RecordType, &'v KeyOptions) -> O,
 {
-    let db_response = get_fn(con, query.name(), query.query_type()).await?;
-    let db_entry = match db_response {
-        QueryResponse::Empty => return Ok(vec![]),
-        QueryResponse::Definitive(def) => def,
-        QueryResponse::Wildcard(wild) => wild,
-        QueryResponse::Both { definitive, .. } => definitive,
+    let (db_response, variant) = get_fn(con, query.name(), query.query_type(), options).await?;
+    let (db_entry, wildcard) = match db_response {
+        QueryResponse::Empty => return Ok(Answers::default()),
+        QueryResponse::Definitive(def) => (def, false),
+        QueryResponse::Wildcard(wild) => (wild, true),
+        QueryResponse::Both { definitive, .. } => (definitive, false),
+    };
+    let mut records = db_entry.convert()?;
+    if wildcard {
+        for record in &mut records {
+            record.set_name(query.name().clone());
+        }
+    }
+    Ok(Answers {
+        records,
+        wildcard,
+        variant,
+    })
+}
+
+/// Finds the RRSIG records of the RRset that the given answers to the query were taken from.
+async fn find_rrsigs(
+    query: &Query,
+    answers: &Answers,
+    options: &KeyOptions,
+    dnssec_con: &mut Connection,
+) -> PektinResult<Answers> {
+    if answers.records.is_empty() {
+        return Ok(Answers::default());
+    }
+    // the RRSIG records have to belong to the same variant as the RRset
+    let rrsig_options = KeyOptions {
+        prefix: options.prefix.clone(),
+        variants: answers.variant.iter().cloned().collect(),
+    };
+    find_answers(query, get_rrsig, &rrsig_options, dnssec_con).await
+}
+
+/// Synthesizes the answers to an A or AAAA query for a name with an ALIAS record.
+///
+/// Returns `None` if the target is in our zones and `allowed` (which checks the zone ACLs) doesn't
+/// allow the client to query it.
+async fn resolve_alias(
+    query: &Query,
+    alias: &Alias,
+    options: &KeyOptions,
+    state: &ServerState,
+    allowed: impl Fn(&Name) -> bool,
+    con: &mut Connection,
+) -> anyhow::Result<Option<Vec<Record>>> {
+    let target = Name::from_utf8(&alias.target)
+        .map_err(|_| anyhow!("ALIAS target {} is not a valid DNS name", alias.target))?;
+    let records = if find_authoritative_zone(&target, options, con)
+        .await?
+        .is_some()
+    {
+        if !allowed(&target) {
+            return Ok(None);
+        }
+        let target_query = Query::query(target, query.query_type());
+        find_answers(&target_query, get_rrset, options, con)
+            .await?
+            .records
+            .into_iter()
+            .filter_map(|record| {
+                let ttl = record.ttl();
+                record.into_data().map(|data| (data, ttl))
+            })
+            .collect()
+    } else {
+        state
+            .alias_resolver
+            .resolve(&target, query.query_type())
+            .await
+            .with_context(|| format!("Could not resolve ALIAS target {}", target))?
     };
-    Ok(db_entry.convert()?)
+    Ok(Some(
+        records
+            .into_iter()
+            .map(|(data, ttl)| Record::from_rdata(query.name().clone(), ttl.min(alias.ttl), data))
+            .collect(),
+    ))
+}
+
+/// Removes the unhealthy records from the answers or replaces them with the backup RRset.
+///
+/// The RRSIG records only match the complete RRset, so no records are removed from a signed
+/// RRset: the backup RRset (which has its own RRSIG records) is used if any record is unhealthy,
+/// and without a backup RRset, the complete RRset is served. If all records are unhealthy and
+/// there is no backup RRset, the complete RRset is served as well.
+async fn apply_health(
+    mut answers: Answers,
+    health: &RrsetHealth,
+    query: &Query,
+    options: &KeyOptions,
+    signed: bool,
+    con: &mut Connection,
+) -> PektinResult<Answers> {
+    if health.unhealthy.is_empty() {
+        return Ok(answers);
+    }
+    let all_unhealthy = health.all_unhealthy(&answers.records);
+
+    if health.backup && (signed || all_unhealthy) {
+        let backup_options = KeyOptions {
+            prefix: options.prefix.clone(),
+            variants: vec![BACKUP_VARIANT.into()],
+        };
+        let backup = find_answers(query, get_rrset, &backup_options, con).await?;
+        // find_answers() falls back to the default RRset if there is no backup RRset
+        if backup.variant.as_deref() == Some(BACKUP_VARIANT) {
+            return Ok(backup);
+        }
+    }
+    if !signed && !all_unhealthy {
+        answers.records.retain(|record| health.is_healthy(record));
+    }
+    Ok(answers)
+}
+
+/// Adds the NSEC3 record covering the given name and its RRSIG to the authority section, proving
+/// that the name doesn't exist in the given zone.
+///
+/// Does nothing if the zone has no NSEC3PARAM record, i.e. if it isn't signed. If the signer
+/// hasn't published the NSEC3 hashes of the zone, the proof is left out and the response gets an
+/// NSEC Missing extended error instead.
+async fn add_covering_nsec3(
+    response: &mut Message,
+    name: &Name,
+    zone: &Name,
+    options: &KeyOptions,
+    dnssec_con: &mut Connection,
+) -> anyhow::Result<()> {
+    // the NSEC3 chain has no variants
+    let options = KeyOptions::with_prefix(options.prefix.clone());
+    let nsec3param = find_answers(
+        &Query::query(zone.clone(), RecordType::NSEC3PARAM),
+        get_rrset,
+        &options,
+        dnssec_con,
+    )
+    .await
+    .context("Could not get NSEC3PARAM record")?;
+    let nsec3param = nsec3param
+        .records
+        .into_iter()
+        .find_map(|record| match record.into_data() {
+            Some(RData::DNSSEC(DNSSECRData::NSEC3PARAM(param))) => Some(param),
+            _ => None,
+        });
+    let nsec3param = match nsec3param {
+        Some(param) => param,
+        None => return Ok(()),
+    };
+
+    let hash = nsec3param
+        .hash_algorithm()
+        .hash(nsec3param.salt(), name, nsec3param.iterations())
+        .context("Could not calculate NSEC3 hash")?;
+    let hash = BASE32HEX_NOPAD.encode(hash.as_ref()).to_ascii_lowercase();
+
+    let covering_hash = match get_covering_nsec3_hash(dnssec_con, zone, &hash, &options)
+        .await
+        .context("Could not get covering NSEC3 hash")?
+    {
+        Some(h) => h,
+        None => {
+            warn!("No NSEC3 hashes for zone {}", zone);
+            ExtendedError::NsecMissing.add_to(response, None);
+            return Ok(());
+        }
+    };
+    let owner = Name::from_ascii(&covering_hash)?.append_domain(zone)?;
+
+    let nsec3 = find_answers(
+        &Query::query(owner.clone(), RecordType::NSEC3),
+        get_rrset,
+        &options,
+        dnssec_con,
+    )
+    .await?;
+    let rrsig = find_answers(
+        &Query::query(owner, RecordType::NSEC3),
+        get_rrsig,
+        &options,
+        dnssec_con,
+    )
+    .await?;
+
+    // the name is a bit misleading; this adds the records to the authority section
+    response.add_name_servers(nsec3.records);
+    response.add_name_servers(rrsig.records);
+
+    Ok(())
 }
 
 /// Assumes the given query matched no known records. Adds the SOA record for the given zone to the
@@ -191,12 +1185,14 @@ async fn add_soa_and_nsec3(
     query: &Query,
     authoritative_zone: Name,
     do_flag: bool,
+    options: &KeyOptions,
     con: &mut Connection,
     dnssec_con: &mut Connection,
 ) -> anyhow::Result<()> {
     // TODO: generate NSEC3 record and include it as well as its RRSIG record
 
-    let db_response = get_rrset(con, &authoritative_zone, RecordType::SOA)
+    let options = KeyOptions::with_prefix(options.prefix.clone());
+    let (db_response, _) = get_rrset(con, &authoritative_zone, RecordType::SOA, &options)
         .await
         .context("Could not get SOA record")?;
 
@@ -238,13 +1234,113 @@ async fn add_soa_and_nsec3(
         let rrsig = find_answers(
             &Query::query(soa_name, RecordType::SOA),
             get_rrsig,
+            &options,
             dnssec_con,
         )
         .await?;
 
         // the name is a bit misleading; this adds the records to the authority section
-        response.add_name_servers(rrsig);
+        response.add_name_servers(rrsig.records);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(class: DNSClass, rr_type: RecordType) -> Message {
+        let mut query = Query::query(Name::from_ascii("example.com.").unwrap(), rr_type);
+        query.set_query_class(class);
+        let mut message = Message::new();
+        message.add_query(query);
+        message
+    }
+
+    fn opt_record() -> Record {
+        let mut record = Record::with(Name::root(), RecordType::OPT, 0);
+        record.set_data(Some(RData::OPT(Default::default())));
+        record
+    }
+
+    #[test]
+    fn validates_requests() {
+        let query = || request(DNSClass::IN, RecordType::A);
+
+        let mut notify = query();
+        notify.set_op_code(OpCode::Notify);
+        let mut update = query();
+        update.set_op_code(OpCode::Update);
+        // responses are dropped before they are validated (see process_request())
+        let mut response = query();
+        response.set_message_type(MessageType::Response);
+        let mut edns_v1 = query();
+        let mut edns = Edns::new();
+        edns.set_version(1);
+        edns_v1.set_edns(edns);
+        let mut edns_v0 = query();
+        edns_v0.set_edns(Edns::new());
+        let mut opt_in_answers = query();
+        opt_in_answers.add_answer(opt_record());
+        let mut two_queries = query();
+        two_queries.add_query(Query::query(Name::root(), RecordType::A));
+
+        let cases = [
+            ("A query", query(), Ok(())),
+            ("NOTIFY", notify, Err(ResponseCode::NotImp)),
+            ("UPDATE", update, Err(ResponseCode::NotImp)),
+            ("QR=1", response, Ok(())),
+            ("EDNS version 1", edns_v1, Err(ResponseCode::BADVERS)),
+            ("EDNS version 0", edns_v0, Ok(())),
+            ("OPT in answers", opt_in_answers, Err(ResponseCode::FormErr)),
+            ("two queries", two_queries, Err(ResponseCode::FormErr)),
+            ("no query", Message::new(), Err(ResponseCode::FormErr)),
+            ("CH class", request(DNSClass::CH, RecordType::TXT), Ok(())),
+            (
+                "NONE class",
+                request(DNSClass::NONE, RecordType::A),
+                Err(ResponseCode::NotImp),
+            ),
+            (
+                "ANY class",
+                request(DNSClass::ANY, RecordType::A),
+                Err(ResponseCode::NotImp),
+            ),
+            (
+                "HS class",
+                request(DNSClass::HS, RecordType::A),
+                Err(ResponseCode::Refused),
+            ),
+            (
+                "OPT type",
+                request(DNSClass::IN, RecordType::OPT),
+                Err(ResponseCode::FormErr),
+            ),
+            (
+                "AXFR",
+                request(DNSClass::IN, RecordType::AXFR),
+                Err(ResponseCode::Refused),
+            ),
+            (
+                "IXFR",
+                request(DNSClass::IN, RecordType::IXFR),
+                Err(ResponseCode::Refused),
+            ),
+            (
+                "MAILA",
+                request(DNSClass::IN, RecordType::from(MAILA_TYPE)),
+                Err(ResponseCode::NotImp),
+            ),
+            (
+                "MAILB",
+                request(DNSClass::IN, RecordType::from(MAILB_TYPE)),
+                Err(ResponseCode::NotImp),
+            ),
+            ("ANY type", request(DNSClass::IN, RecordType::ANY), Ok(())),
+        ];
+        for (description, message, expected) in cases {
+            assert_eq!(validate_request(&message), expected, "{}", description);
+        }
+    }
+}
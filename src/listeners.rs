@@ -0,0 +1,59 @@
+use std::net::SocketAddr;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Returns whether IPv6 sockets must be IPv6-only, which is the case if IPv4 addresses are
+/// listened on separately (a dual-stack socket would conflict with them).
+pub fn v6_only(addresses: &[SocketAddr]) -> bool {
+    addresses.iter().any(SocketAddr::is_ipv4)
+}
+
+fn bind_socket(address: SocketAddr, ty: Type, v6_only: bool) -> std::io::Result<Socket> {
+    let protocol = match ty {
+        Type::DGRAM => Protocol::UDP,
+        _ => Protocol::TCP,
+    };
+    let socket = Socket::new(Domain::for_address(address), ty, Some(protocol))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Binds a UDP socket, setting `SO_REUSEPORT` so that multiple sockets can be bound to the same
+/// address if `reuse_port` is true.
+pub fn bind_udp(
+    address: SocketAddr,
+    reuse_port: bool,
+    v6_only: bool,
+) -> std::io::Result<std::net::UdpSocket> {
+    let socket = bind_socket(address, Type::DGRAM, v6_only)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&address.into())?;
+    Ok(socket.into())
+}
+
+/// Binds a TCP socket and starts listening on it.
+pub fn bind_tcp(address: SocketAddr, v6_only: bool) -> std::io::Result<std::net::TcpListener> {
+    let socket = bind_socket(address, Type::STREAM, v6_only)?;
+    // like tokio and actix do, so that restarts don't fail because of connections in TIME_WAIT
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Binds a UDP socket to an ephemeral port of the address family of `peer` and connects it to
+/// `peer`, for sending queries to other servers.
+pub async fn connect_udp(peer: SocketAddr) -> std::io::Result<tokio::net::UdpSocket> {
+    let bind_addr: SocketAddr = match peer {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = tokio::net::UdpSocket::bind(bind_addr).await?;
+    socket.connect(peer).await?;
+    Ok(socket)
+}
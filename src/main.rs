@@ -1,81 +1,46 @@
 mod doh;
 
 use std::io::Write;
-use std::net::Ipv6Addr;
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use futures_util::future::{pending, select_all};
 use futures_util::StreamExt;
-use log::{error, warn};
-use pektin_common::deadpool_redis::{self, Pool};
-use pektin_common::load_env;
+use log::{error, info, warn};
 use pektin_common::proto::iocompat::AsyncIoTokioAsStd;
 use pektin_common::proto::op::Message;
 use pektin_common::proto::tcp::TcpStream;
-use pektin_common::proto::udp::UdpStream;
 use pektin_common::proto::xfer::{BufDnsStreamHandle, SerialMessage};
 use pektin_common::proto::DnsStreamHandle;
-use pektin_server::{process_request, PektinResult};
-use tokio::net::{TcpListener, UdpSocket};
+use pektin_server::acl::Acls;
+use pektin_server::alias::AliasResolver;
+use pektin_server::config::Config;
+use pektin_server::connections::{ClosableReads, TcpConnections};
+use pektin_server::cookies::CookieSecrets;
+use pektin_server::geoip::GeoIp;
+use pektin_server::health::Health;
+use pektin_server::listeners;
+use pektin_server::metrics::{self, Metrics};
+use pektin_server::proxy::{self, ProxyHeader};
+use pektin_server::queue::DropOldestQueue;
+use pektin_server::ratelimit::TokenBucket;
+use pektin_server::regions::RegionMap;
+use pektin_server::rrl::ResponseRateLimiter;
+use pektin_server::tsig::TsigKeys;
+use pektin_server::udp::{PacketInfo, UdpListener};
+use pektin_server::views::Views;
+use pektin_server::{
+    format_error_response, process_request, PektinError, PektinResult, RequestInfo, ServerState,
+    Transport,
+};
+use tokio::net::TcpListener;
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
+use tokio::time::{timeout, timeout_at};
 use trust_dns_server::server::TimeoutStream;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct Config {
-    pub bind_address: Ipv6Addr,
-    pub bind_port: u16,
-    pub db_hostname: String,
-    pub db_username: String,
-    pub db_password: String,
-    pub db_port: u16,
-    pub db_retry_seconds: u64,
-    pub tcp_timeout_seconds: u64,
-    pub use_doh: bool,
-    pub doh_bind_address: Ipv6Addr,
-    pub doh_bind_port: u16,
-}
-
-impl Config {
-    pub fn from_env() -> PektinResult<Self> {
-        Ok(Self {
-            bind_address: load_env("::", "BIND_ADDRESS", false)?
-                .parse()
-                .map_err(|_| {
-                    pektin_common::PektinCommonError::InvalidEnvVar("BIND_ADDRESS".into())
-                })?,
-            bind_port: load_env("53", "BIND_PORT", false)?
-                .parse()
-                .map_err(|_| pektin_common::PektinCommonError::InvalidEnvVar("BIND_PORT".into()))?,
-            db_hostname: load_env("pektin-db", "DB_HOSTNAME", false)?,
-            db_port: load_env("6379", "DB_PORT", false)?
-                .parse()
-                .map_err(|_| pektin_common::PektinCommonError::InvalidEnvVar("DB_PORT".into()))?,
-            db_username: load_env("db-pektin-server", "DB_USERNAME", false)?,
-            db_password: load_env("", "DB_PASSWORD", true)?,
-            db_retry_seconds: load_env("1", "DB_RETRY_SECONDS", false)?
-                .parse()
-                .map_err(|_| {
-                    pektin_common::PektinCommonError::InvalidEnvVar("DB_RETRY_SECONDS".into())
-                })?,
-            tcp_timeout_seconds: load_env("3", "TCP_TIMEOUT_SECONDS", false)?
-                .parse()
-                .map_err(|_| {
-                    pektin_common::PektinCommonError::InvalidEnvVar("TCP_TIMEOUT_SECONDS".into())
-                })?,
-            use_doh: load_env("true", "USE_DOH", false)? == "true",
-            doh_bind_port: load_env("80", "DOH_BIND_PORT", false)?
-                .parse()
-                .map_err(|_| {
-                    pektin_common::PektinCommonError::InvalidEnvVar("DOH_BIND_PORT".into())
-                })?,
-            doh_bind_address: load_env("::", "DOH_BIND_ADDRESS", false)?
-                .parse()
-                .map_err(|_| {
-                    pektin_common::PektinCommonError::InvalidEnvVar("DOH_BIND_ADDRESS".into())
-                })?,
-        })
-    }
-}
-
 #[tokio::main]
 async fn main() -> PektinResult<()> {
     env_logger::builder()
@@ -95,37 +60,94 @@ async fn main() -> PektinResult<()> {
     println!("Started Pektin with these globals:");
     let config = Config::from_env()?;
 
-    let db_pool_conf = deadpool_redis::Config {
-        url: Some(format!(
-            "redis://{}:{}@{}:{}/0",
-            config.db_username, config.db_password, config.db_hostname, config.db_port
-        )),
-        connection: None,
-        pool: None,
+    let db_pool = config.create_db_pool(0)?;
+    let db_pool_dnssec = config.create_db_pool(1)?;
+
+    let regions = if config.regions_file.is_empty() {
+        RegionMap::default()
+    } else {
+        RegionMap::from_file(&config.regions_file)?
     };
-    let db_pool = db_pool_conf.create_pool(Some(deadpool_redis::Runtime::Tokio1))?;
-
-    let db_pool_dnssec_conf = deadpool_redis::Config {
-        url: Some(format!(
-            "redis://{}:{}@{}:{}/1",
-            config.db_username, config.db_password, config.db_hostname, config.db_port
-        )),
-        connection: None,
-        pool: None,
+
+    let geoip = GeoIp::open(&config.geoip_databases)?;
+
+    let tsig_keys = if config.tsig_keys_file.is_empty() {
+        TsigKeys::default()
+    } else {
+        TsigKeys::from_file(&config.tsig_keys_file)?
     };
-    let db_pool_dnssec = db_pool_dnssec_conf.create_pool(Some(deadpool_redis::Runtime::Tokio1))?;
 
-    let doh_db_pool = db_pool.clone();
-    let doh_db_pool_dnssec = db_pool_dnssec.clone();
+    let views = if config.views_file.is_empty() {
+        Views::default()
+    } else {
+        Views::from_file(&config.views_file, &config)?
+    };
+
+    let acls = if config.acls_file.is_empty() {
+        Acls::default()
+    } else {
+        Acls::from_file(&config.acls_file)?
+    };
+
+    let cookie_secrets = CookieSecrets::parse(&config.cookie_secrets)?;
+
+    let state = Arc::new(ServerState {
+        config: config.clone(),
+        db_pool,
+        db_pool_dnssec,
+        regions,
+        geoip,
+        health: Health::default(),
+        alias_resolver: AliasResolver::new(config.alias_resolver),
+        tsig_keys,
+        cookie_secrets,
+        metrics: Metrics::default(),
+        format_error_limiter: TokenBucket::new(
+            config.format_errors_per_second,
+            config.format_errors_per_second,
+        ),
+        rrl: ResponseRateLimiter::from_config(&config),
+        views,
+        acls,
+        tcp_connections: TcpConnections::from_config(&config),
+        in_flight_queries: Arc::new(Semaphore::new(config.max_in_flight_queries.max(1))),
+    });
+
+    if !state.geoip.is_empty() {
+        let geoip_state = state.clone();
+        let reload_interval = Duration::from_secs(config.geoip_reload_seconds);
+        tokio::spawn(async move {
+            geoip_state.geoip.watch(reload_interval).await;
+        });
+    }
+
+    // only some servers have to run the health checks, the others use their published results
+    let health_state = state.clone();
+    let health_interval = Duration::from_secs(config.health_check_interval_seconds);
+    let probe_timeout = Duration::from_secs(config.health_check_timeout_seconds);
+    let run_health_checks = config.health_checks;
+    tokio::spawn(async move {
+        let health = &health_state.health;
+        if run_health_checks {
+            health
+                .check(&health_state.db_pool, health_interval, probe_timeout)
+                .await;
+        } else {
+            health.follow(&health_state.db_pool, health_interval).await;
+        }
+    });
+
+    let acl_state = state.clone();
+    let acl_interval = Duration::from_secs(config.acl_reload_seconds);
+    tokio::spawn(async move {
+        acl_state
+            .acls
+            .follow(&acl_state.db_pool, acl_interval)
+            .await;
+    });
+
     let doh_server = if config.use_doh {
-        match doh::use_doh(
-            config.doh_bind_address,
-            config.doh_bind_port,
-            doh_db_pool,
-            doh_db_pool_dnssec,
-        )
-        .await
-        {
+        match doh::use_doh(&config.doh_listen_addresses, state.clone()).await {
             Ok(server) => Some(server),
             Err(e) => {
                 error!("Error while trying to start DOH server: {}", e);
@@ -136,74 +158,147 @@ async fn main() -> PektinResult<()> {
         None
     };
 
-    let udp_db_pool = db_pool.clone();
-    let udp_db_pool_dnssec = db_pool_dnssec.clone();
-    let udp_socket =
-        UdpSocket::bind(format!("[{}]:{}", &config.bind_address, config.bind_port)).await?;
-    let udp_join_handle = tokio::spawn(async move {
-        message_loop_udp(udp_socket, udp_db_pool, udp_db_pool_dnssec).await;
-    });
+    let metrics_server = match config.metrics_address {
+        Some(address) => match metrics::use_metrics(address, state.clone()) {
+            Ok(server) => Some(server),
+            Err(e) => {
+                error!("Error while trying to start metrics server: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
 
-    let tcp_db_pool = db_pool.clone();
-    let tcp_db_pool_dnssec = db_pool_dnssec.clone();
-    let tcp_listener =
-        TcpListener::bind(format!("[{}]:{}", &config.bind_address, config.bind_port)).await?;
-    let tcp_join_handle = tokio::spawn(async move {
-        message_loop_tcp(tcp_listener, tcp_db_pool, tcp_db_pool_dnssec).await;
-    });
+    // each UDP socket gets its own receive loop, the kernel distributes the queries among them
+    let udp_sockets = match config.udp_sockets {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let v6_only = listeners::v6_only(&config.listen_addresses);
+    let mut join_handles = vec![];
+    for &address in &config.listen_addresses {
+        for _ in 0..udp_sockets {
+            let udp_state = state.clone();
+            let udp_socket = UdpListener::new(
+                listeners::bind_udp(address, udp_sockets > 1, v6_only)?,
+                config.udp_batch_size,
+            )?;
+            join_handles.push(tokio::spawn(async move {
+                message_loop_udp(udp_socket, udp_state).await;
+            }));
+        }
+
+        let tcp_state = state.clone();
+        let tcp_listener = TcpListener::from_std(listeners::bind_tcp(address, v6_only)?)?;
+        join_handles.push(tokio::spawn(async move {
+            message_loop_tcp(tcp_listener, tcp_state).await;
+        }));
+    }
+    if join_handles.is_empty() {
+        return Err(PektinError::InvalidConfig(
+            "no listen addresses configured".into(),
+        ));
+    }
+    // the server stops if any of the listeners stops
+    let listeners_join_handle = select_all(join_handles);
 
     // shutdown if we receive a SIGINT (Ctrl+C) or SIGTERM (sent by docker on shutdown)
     let mut sigint = signal(SignalKind::interrupt())?;
     let mut sigterm = signal(SignalKind::terminate())?;
-    match doh_server {
-        Some(server) => {
-            tokio::select! {
-                _ = udp_join_handle => Ok(()),
-                _ = tcp_join_handle => Ok(()),
-                res = server => res.map_err(Into::into),
-                _ = sigint.recv() => Ok(()),
-                _ = sigterm.recv() => Ok(()),
-            }
-        }
-        None => {
-            tokio::select! {
-                _ = udp_join_handle => (),
-                _ = tcp_join_handle => (),
-                _ = sigint.recv() => (),
-                _ = sigterm.recv() => (),
-            };
-            Ok(())
+    let http_servers: Vec<_> = doh_server.into_iter().chain(metrics_server).collect();
+    let http_servers_join_handle = async move {
+        if http_servers.is_empty() {
+            pending().await
+        } else {
+            select_all(http_servers).await.0
         }
+    };
+    tokio::select! {
+        _ = listeners_join_handle => Ok(()),
+        res = http_servers_join_handle => res.map_err(Into::into),
+        _ = sigint.recv() => Ok(()),
+        _ = sigterm.recv() => Ok(()),
     }
 }
 
-async fn message_loop_udp(socket: UdpSocket, db_pool: Pool, db_pool_dnssec: Pool) {
-    // see trust_dns_server::server::ServerFuture::register_socket
-    let (mut udp_stream, udp_handle) =
-        UdpStream::with_bound(socket, ([127, 255, 255, 254], 0).into());
-    while let Some(message) = udp_stream.next().await {
-        let message = match message {
-            Ok(m) => m,
+/// Where to send the response to a query.
+enum Responder {
+    /// The socket, the address to send the response to, which differs from the client's address
+    /// when a load balancer uses the PROXY protocol, and the local address to send it from.
+    Udp(Arc<UdpListener>, SocketAddr, Option<PacketInfo>),
+    Tcp(BufDnsStreamHandle),
+}
+
+async fn message_loop_udp(socket: UdpListener, state: Arc<ServerState>) {
+    let socket = Arc::new(socket);
+    // queries are queued instead of being processed right away, so that we drop the oldest ones
+    // instead of running out of memory when we receive more than we can handle
+    let queue = Arc::new(DropOldestQueue::new(state.config.udp_queue_size));
+    tokio::spawn(dispatch_udp(queue.clone(), state.clone()));
+    loop {
+        let datagrams = match socket.recv().await {
+            Ok(datagrams) => datagrams,
             Err(e) => {
                 warn!("Error receiving UDP message: {}", e);
                 continue;
             }
         };
+        for (mut bytes, src_addr, pktinfo) in datagrams {
+            // behind a load balancer, each datagram starts with the address of the actual client,
+            // but the response goes back to the load balancer
+            let client_addr =
+                if proxy::is_trusted(src_addr.ip(), &state.config.proxy_protocol_networks) {
+                    match ProxyHeader::parse(&bytes) {
+                        Ok(header) => {
+                            bytes.drain(..header.len);
+                            header.source.unwrap_or(src_addr)
+                        }
+                        Err(e) => {
+                            warn!("Invalid PROXY protocol header from {}: {}", src_addr, e);
+                            continue;
+                        }
+                    }
+                } else {
+                    src_addr
+                };
 
-        let src_addr = message.addr();
-        let udp_handle = udp_handle.with_remote_addr(src_addr);
-        let req_db_pool = db_pool.clone();
-        let req_db_pool_dnssec = db_pool_dnssec.clone();
+            // the response is sent from the address the query was sent to
+            let message = SerialMessage::new(bytes, client_addr);
+            let responder = Responder::Udp(socket.clone(), src_addr, pktinfo);
+            if queue.push((message, responder, Instant::now())).is_some() {
+                state
+                    .metrics
+                    .shed_udp_queries
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Takes the queued UDP queries and processes them as soon as there is capacity for them.
+async fn dispatch_udp(
+    queue: Arc<DropOldestQueue<(SerialMessage, Responder, Instant)>>,
+    state: Arc<ServerState>,
+) {
+    loop {
+        // waiting for a query with a permit would keep the permit from the TCP listeners
+        let (message, responder, received) = queue.pop().await;
+        let permit = match state.in_flight_queries.clone().acquire_owned().await {
+            Ok(p) => p,
+            Err(_) => return,
+        };
+        let req_state = state.clone();
         tokio::spawn(async move {
-            handle_request_udp_tcp(message, udp_handle, req_db_pool, req_db_pool_dnssec).await;
+            handle_request_udp_tcp(message, responder, Transport::Udp, received, &req_state).await;
+            drop(permit);
         });
     }
 }
 
-async fn message_loop_tcp(listener: TcpListener, db_pool: Pool, db_pool_dnssec: Pool) {
+async fn message_loop_tcp(listener: TcpListener, state: Arc<ServerState>) {
     // see trust_dns_server::server::ServerFuture::register_listener
     loop {
-        let tcp_stream = match listener.accept().await {
+        let mut tcp_stream = match listener.accept().await {
             Ok((t, _)) => t,
             Err(e) => {
                 warn!("Error creating a new TCP stream: {}", e);
@@ -211,8 +306,7 @@ async fn message_loop_tcp(listener: TcpListener, db_pool: Pool, db_pool_dnssec:
             }
         };
 
-        let req_db_pool = db_pool.clone();
-        let req_db_pool_dnssec = db_pool_dnssec.clone();
+        let req_state = state.clone();
         tokio::spawn(async move {
             let src_addr = match tcp_stream.peer_addr() {
                 Ok(addr) => addr,
@@ -221,28 +315,93 @@ async fn message_loop_tcp(listener: TcpListener, db_pool: Pool, db_pool_dnssec:
                     return;
                 }
             };
+            // behind a load balancer, the connection starts with the address of the actual client
+            let header_timeout = Duration::from_secs(req_state.config.tcp_timeout_seconds);
+            let src_addr = match proxy::client_addr(
+                &mut tcp_stream,
+                src_addr,
+                &req_state.config.proxy_protocol_networks,
+                header_timeout,
+            )
+            .await
+            {
+                Some(addr) => addr,
+                None => return,
+            };
 
+            let (tcp_stream, read_closer) = ClosableReads::new(tcp_stream);
             let (tcp_stream, tcp_handle) =
                 TcpStream::from_stream(AsyncIoTokioAsStd(tcp_stream), src_addr);
-            // TODO maybe make this configurable via environment variable?
-            let mut timeout_stream = TimeoutStream::new(tcp_stream, Duration::from_secs(3));
+            let tcp_timeout = Duration::from_secs(req_state.config.tcp_timeout_seconds);
+            let mut timeout_stream = TimeoutStream::new(tcp_stream, tcp_timeout);
+            let connection = req_state
+                .tcp_connections
+                .open(src_addr.ip(), &req_state.metrics);
+            let max_queries = req_state.config.tcp_max_queries_per_connection;
 
-            while let Some(message) = timeout_stream.next().await {
+            // pipelined queries are processed concurrently and answered out of order (see
+            // RFC 7766, section 6.2.1.1)
+            let max_in_flight = req_state.config.tcp_max_in_flight.max(1);
+            let in_flight = Arc::new(Semaphore::new(max_in_flight));
+            let mut queries = 0;
+            loop {
+                let message = tokio::select! {
+                    message = timeout_stream.next() => message,
+                    _ = connection.evicted() => break,
+                };
                 let message = match message {
-                    Ok(m) => m,
-                    Err(e) => {
+                    Some(Ok(m)) => m,
+                    Some(Err(e)) => {
                         warn!("Error receiving TCP message: {}", e);
                         return;
                     }
+                    None => break,
                 };
+                connection.touch();
 
-                handle_request_udp_tcp(
-                    message,
-                    tcp_handle.clone(),
-                    req_db_pool.clone(),
-                    req_db_pool_dnssec.clone(),
-                )
-                .await;
+                let received = Instant::now();
+                // stop reading from the connection while too many of its queries or too many
+                // queries in total are in flight
+                let permits = match (
+                    in_flight.clone().acquire_owned().await,
+                    req_state.in_flight_queries.clone().acquire_owned().await,
+                ) {
+                    (Ok(p), Ok(global_p)) => (p, global_p),
+                    _ => return,
+                };
+                let responder = Responder::Tcp(tcp_handle.clone());
+                let query_state = req_state.clone();
+                tokio::spawn(async move {
+                    handle_request_udp_tcp(
+                        message,
+                        responder,
+                        Transport::Tcp,
+                        received,
+                        &query_state,
+                    )
+                    .await;
+                    drop(permits);
+                });
+
+                queries += 1;
+                if max_queries != 0 && queries >= max_queries {
+                    break;
+                }
+            }
+
+            // the responses are only written while the stream is polled, so wait for the queries
+            // still in flight and poll it until it has written all responses: it only reads after
+            // sending everything, and once the responders are gone and reading is closed, that
+            // ends the stream
+            let _ = in_flight.acquire_many(max_in_flight as u32).await;
+            drop(tcp_handle);
+            read_closer.close();
+            let flush = async { while let Some(Ok(_)) = timeout_stream.next().await {} };
+            if timeout(tcp_timeout, flush).await.is_err() {
+                warn!(
+                    "Timed out writing the remaining TCP responses to {}",
+                    src_addr
+                );
             }
         });
     }
@@ -250,22 +409,49 @@ async fn message_loop_tcp(listener: TcpListener, db_pool: Pool, db_pool_dnssec:
 
 async fn handle_request_udp_tcp(
     msg: SerialMessage,
-    stream_handle: BufDnsStreamHandle,
-    db_pool: Pool,
-    db_pool_dnssec: Pool,
+    responder: Responder,
+    transport: Transport,
+    received: Instant,
+    state: &ServerState,
 ) {
     let message = match msg.to_message() {
         Ok(m) => m,
         _ => {
             warn!("Could not deserialize received message");
+            if let Some(response_bytes) = format_error_response(msg.bytes(), state) {
+                send_bytes(msg, response_bytes, responder).await;
+            }
             return;
         }
     };
-    let response = process_request(message, db_pool, db_pool_dnssec).await;
-    send_response(msg, response, stream_handle)
+    let request_info = RequestInfo {
+        transport,
+        client_ip: Some(msg.addr().ip()),
+        raw_message: msg.bytes(),
+    };
+    // the client has most likely given up on the query after its deadline
+    let deadline = received + Duration::from_millis(state.config.query_deadline_milliseconds);
+    let response = match timeout_at(
+        deadline.into(),
+        process_request(message, request_info, state),
+    )
+    .await
+    {
+        Ok(Some(r)) => r,
+        Ok(None) => return,
+        Err(_) => {
+            info!("Dropping query that could not be answered before its deadline");
+            state
+                .metrics
+                .expired_queries
+                .fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    send_response(msg, response, responder).await
 }
 
-fn send_response(query: SerialMessage, response: Message, mut stream_handle: BufDnsStreamHandle) {
+async fn send_response(query: SerialMessage, response: Message, responder: Responder) {
     let response_bytes = match response.to_vec() {
         Ok(b) => b,
         Err(e) => {
@@ -273,8 +459,20 @@ fn send_response(query: SerialMessage, response: Message, mut stream_handle: Buf
             return;
         }
     };
-    let serialized_response = SerialMessage::new(response_bytes, query.addr());
-    if let Err(e) = stream_handle.send(serialized_response) {
+    send_bytes(query, response_bytes, responder).await
+}
+
+async fn send_bytes(query: SerialMessage, response_bytes: Vec<u8>, responder: Responder) {
+    let result = match responder {
+        Responder::Udp(socket, dst_addr, pktinfo) => socket
+            .send(&response_bytes, dst_addr, pktinfo)
+            .await
+            .map_err(|e| e.to_string()),
+        Responder::Tcp(mut stream_handle) => stream_handle
+            .send(SerialMessage::new(response_bytes, query.addr()))
+            .map_err(|e| e.to_string()),
+    };
+    if let Err(e) = result {
         warn!("Could not send response: {}", e);
     }
 }
@@ -0,0 +1,102 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use actix_web::dev::Server;
+use actix_web::{get, web, App, HttpResponse, HttpServer};
+
+use crate::{listeners, PektinResult, ServerState};
+
+/// Counters describing the server's operation, exposed in the Prometheus text format at
+/// `/metrics` of the metrics server (see [`use_metrics`]).
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Requests that couldn't be parsed.
+    pub malformed_requests: AtomicU64,
+    /// FORMERR responses to malformed requests that were not sent because of the rate limit.
+    pub suppressed_format_errors: AtomicU64,
+    /// Responses dropped by response rate limiting.
+    pub rrl_dropped_responses: AtomicU64,
+    /// Responses replaced by truncated responses by response rate limiting.
+    pub rrl_slipped_responses: AtomicU64,
+    /// Idle TCP connections closed to make room for new ones.
+    pub evicted_tcp_connections: AtomicU64,
+    /// UDP queries dropped from the full queue to make room for new ones.
+    pub shed_udp_queries: AtomicU64,
+    /// Queries that were dropped because they couldn't be answered before their deadline.
+    pub expired_queries: AtomicU64,
+}
+
+impl Metrics {
+    /// Renders the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "pektin_malformed_requests_total",
+                "Requests that could not be parsed.",
+                &self.malformed_requests,
+            ),
+            (
+                "pektin_suppressed_format_errors_total",
+                "FORMERR responses to malformed requests suppressed by the rate limit.",
+                &self.suppressed_format_errors,
+            ),
+            (
+                "pektin_rrl_dropped_responses_total",
+                "Responses dropped by response rate limiting.",
+                &self.rrl_dropped_responses,
+            ),
+            (
+                "pektin_rrl_slipped_responses_total",
+                "Responses truncated by response rate limiting.",
+                &self.rrl_slipped_responses,
+            ),
+            (
+                "pektin_evicted_tcp_connections_total",
+                "Idle TCP connections closed to make room for new ones.",
+                &self.evicted_tcp_connections,
+            ),
+            (
+                "pektin_shed_udp_queries_total",
+                "UDP queries dropped from the full queue to make room for new ones.",
+                &self.shed_udp_queries,
+            ),
+            (
+                "pektin_expired_queries_total",
+                "Queries dropped because they could not be answered before their deadline.",
+                &self.expired_queries,
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, help, counter) in counters {
+            // writing to a String can't fail
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} counter", name);
+            let _ = writeln!(output, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        output
+    }
+}
+
+/// Starts the HTTP server that exposes the metrics at `/metrics`.
+///
+/// It has its own listen address so that the metrics aren't reachable by DNS clients.
+pub fn use_metrics(address: SocketAddr, state: Arc<ServerState>) -> PektinResult<Server> {
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::from(state.clone()))
+            .service(metrics)
+    })
+    .workers(1)
+    .listen(listeners::bind_tcp(address, false)?)?;
+    Ok(server.run())
+}
+
+#[get("/metrics")]
+async fn metrics(state: web::Data<ServerState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render())
+}
@@ -1,8 +1,13 @@
+use std::str::FromStr;
+
 use pektin_common::deadpool_redis::redis::aio::Connection;
-use pektin_common::deadpool_redis::redis::{AsyncCommands, FromRedisValue, Value};
-use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::deadpool_redis::redis::{self, AsyncCommands, FromRedisValue, Value};
+use pektin_common::proto::rr::{Name, Record, RecordType};
 use pektin_common::DbEntry;
 
+use crate::alias::Alias;
+use crate::dname::Dname;
+use crate::policy::AnswerPolicy;
 use crate::{PektinError, PektinResult};
 
 pub enum QueryResponse {
@@ -15,66 +20,327 @@ pub enum QueryResponse {
     },
 }
 
-// also automatically looks for a wildcard record
+/// Determines which db keys are used for a lookup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyOptions {
+    /// Prepended to all keys as `<prefix>:`, used to separate the data of views (see
+    /// [`crate::views`]).
+    pub prefix: Option<String>,
+    /// The variants of an RRset to try in the given order before falling back to the default
+    /// RRset. A variant `v` of the RRset stored at `<name>:<type>` is stored at `<name>:<type>@v`.
+    pub variants: Vec<String>,
+}
+
+impl KeyOptions {
+    /// Key options for a lookup that only uses the given prefix and no variants.
+    pub fn with_prefix(prefix: Option<String>) -> Self {
+        Self {
+            prefix,
+            variants: vec![],
+        }
+    }
+
+    /// Returns the db key of the RRset with the given owner name, type and variant.
+    pub fn rrset_key(&self, owner: &Name, rr_type: RecordType, variant: Option<&str>) -> String {
+        let key = format!("{}:{}", owner.to_lowercase(), rr_type);
+        match variant {
+            Some(variant) => self.db_key(&format!("{}@{}", key, variant)),
+            None => self.db_key(&key),
+        }
+    }
+
+    fn db_key(&self, key: &str) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{}:{}", prefix, key),
+            None => key.to_string(),
+        }
+    }
+}
+
+// also automatically looks for a wildcard record and the variants given in the options.
+// the second value of the returned tuple is the variant that was found, if any.
 pub async fn get_rrset(
     con: &mut Connection,
     zone: &Name,
     rr_type: RecordType,
-) -> PektinResult<QueryResponse> {
+    options: &KeyOptions,
+) -> PektinResult<(QueryResponse, Option<String>)> {
     let zone = zone.to_lowercase();
     let definitive_key = format!("{}:{}", zone, rr_type);
     let wildcard_key = format!("{}:{}", zone.clone().into_wildcard(), rr_type);
-    get_definitive_or_wildcard_records(con, &definitive_key, &wildcard_key).await
+    get_definitive_or_wildcard_records(con, &definitive_key, &wildcard_key, options).await
 }
 
 pub async fn get_rrsig(
     con: &mut Connection,
     zone: &Name,
     rr_type: RecordType,
-) -> PektinResult<QueryResponse> {
+    options: &KeyOptions,
+) -> PektinResult<(QueryResponse, Option<String>)> {
     let zone = zone.to_lowercase();
     let definitive_key = format!("{}:RRSIG:{}", zone, rr_type);
     let wildcard_key = format!("{}:RRSIG:{}", zone.clone().into_wildcard(), rr_type);
-    get_definitive_or_wildcard_records(con, &definitive_key, &wildcard_key).await
+    get_definitive_or_wildcard_records(con, &definitive_key, &wildcard_key, options).await
+}
+
+/// Returns the answer policy of the RRset with the given owner name (which may be a wildcard name),
+/// type and variant.
+pub async fn get_answer_policy(
+    con: &mut Connection,
+    owner: &Name,
+    rr_type: RecordType,
+    variant: Option<&str>,
+    options: &KeyOptions,
+) -> PektinResult<AnswerPolicy> {
+    let key = options.rrset_key(owner, rr_type, variant);
+    let policy: Option<String> = con.get(format!("{}:POLICY", key)).await?;
+    match policy {
+        Some(policy) => serde_json::from_str(&policy).map_err(|_| PektinError::InvalidDbData),
+        None => Ok(AnswerPolicy::default()),
+    }
+}
+
+/// Returns whether the RRset with the given owner name (which may be a wildcard name) and type has
+/// any variants (see [`KeyOptions::variants`]), even if none of them are in `options`.
+///
+/// The variants of the RRset stored at `<key>` are stored as a set at `<key>:VARIANTS`.
+pub async fn has_variants(
+    con: &mut Connection,
+    owner: &Name,
+    rr_type: RecordType,
+    options: &KeyOptions,
+) -> PektinResult<bool> {
+    let key = options.rrset_key(owner, rr_type, None);
+    Ok(con.exists(format!("{}:VARIANTS", key)).await?)
+}
+
+/// Returns the ALIAS record of the given name, if it has one.
+pub async fn get_alias(
+    con: &mut Connection,
+    name: &Name,
+    options: &KeyOptions,
+) -> PektinResult<Option<Alias>> {
+    let key = options.db_key(&format!("{}:ALIAS", name.to_lowercase()));
+    let alias: Option<String> = con.get(key).await?;
+    alias
+        .map(|alias| serde_json::from_str(&alias).map_err(|_| PektinError::InvalidDbData))
+        .transpose()
+}
+
+/// Returns the DNAME records of the given names, in the same order.
+pub async fn get_dnames(
+    con: &mut Connection,
+    names: &[Name],
+    options: &KeyOptions,
+) -> PektinResult<Vec<Option<Dname>>> {
+    if names.is_empty() {
+        return Ok(vec![]);
+    }
+    let keys: Vec<_> = names
+        .iter()
+        .map(|name| options.db_key(&format!("{}:DNAME", name.to_lowercase())))
+        .collect();
+    // GET instead of MGET would be used for a single key
+    let values: Vec<Option<String>> = redis::cmd("MGET").arg(&keys).query_async(con).await?;
+    values
+        .into_iter()
+        .map(|value| {
+            value
+                .map(|v| serde_json::from_str(&v).map_err(|_| PektinError::InvalidDbData))
+                .transpose()
+        })
+        .collect()
+}
+
+/// Returns the RRSIG records of the DNAME record at the given name.
+pub async fn get_dname_rrsigs(
+    con: &mut Connection,
+    name: &Name,
+    options: &KeyOptions,
+) -> PektinResult<Vec<Record>> {
+    let key = format!("{}:RRSIG:DNAME", name.to_lowercase());
+    let value: Value = con.get(options.db_key(&key)).await?;
+    match deserialize_db_value(&key, &value)? {
+        Some(entry) => Ok(entry.convert()?),
+        None => Ok(vec![]),
+    }
+}
+
+/// The types whose RRSIG entries are looked up if a name has no `<name>:RRSIGTYPES` set.
+const SIGNED_TYPES: &[&str] = &[
+    "A",
+    "AAAA",
+    "CAA",
+    "CNAME",
+    "DNAME",
+    "DNSKEY",
+    "DS",
+    "MX",
+    "NS",
+    "NSEC3",
+    "NSEC3PARAM",
+    "OPENPGPKEY",
+    "SOA",
+    "SRV",
+    "TLSA",
+    "TXT",
+];
+
+/// Returns the RRSIG entries of all (default) RRsets at the given name.
+///
+/// The types of the signed RRsets at a name are stored by the signer as a set at
+/// `<name>:RRSIGTYPES`. For names without that set, the RRSIG entries of all types the signer
+/// signs are looked up.
+pub async fn get_all_rrsigs(
+    con: &mut Connection,
+    name: &Name,
+    options: &KeyOptions,
+) -> PektinResult<Vec<DbEntry>> {
+    let name = name.to_lowercase();
+    let mut rr_types: Vec<String> = con
+        .smembers(options.db_key(&format!("{}:RRSIGTYPES", name)))
+        .await?;
+    if rr_types.is_empty() {
+        rr_types = SIGNED_TYPES.iter().map(|t| t.to_string()).collect();
+    }
+    let base_keys: Vec<_> = rr_types
+        .iter()
+        .map(|rr_type| format!("{}:RRSIG:{}", name, rr_type))
+        .collect();
+    let keys: Vec<_> = base_keys.iter().map(|key| options.db_key(key)).collect();
+
+    // GET instead of MGET would be used for a single key
+    let values: Vec<Value> = redis::cmd("MGET").arg(&keys).query_async(con).await?;
+    let mut entries = Vec::with_capacity(keys.len());
+    for (base_key, value) in base_keys.iter().zip(values) {
+        entries.extend(deserialize_db_value(base_key, &value)?);
+    }
+    Ok(entries)
+}
+
+/// Returns the types of all RRsets stored for the given name, including RRsets that only exist as
+/// variants.
+///
+/// The types of the RRsets at a name are stored as a set at `<name>:TYPES`.
+pub async fn get_rrset_types(
+    con: &mut Connection,
+    name: &Name,
+    options: &KeyOptions,
+) -> PektinResult<Vec<RecordType>> {
+    let rr_types: Vec<String> = con
+        .smembers(options.db_key(&format!("{}:TYPES", name.to_lowercase())))
+        .await?;
+    let mut rr_types: Vec<RecordType> = rr_types
+        .iter()
+        .filter_map(|rr_type| match RecordType::from_str(rr_type) {
+            Ok(RecordType::Unknown(_)) | Err(_) => None,
+            Ok(t) => Some(t),
+        })
+        .collect();
+    rr_types.sort();
+    Ok(rr_types)
+}
+
+/// Returns the names of all zones we're authoritative for, i.e. all names that have an SOA record.
+pub async fn get_authoritative_zones(
+    con: &mut Connection,
+    options: &KeyOptions,
+) -> PektinResult<Vec<String>> {
+    let prefix = options.db_key("");
+    let keys: Vec<String> = con.keys(format!("{}*.:SOA", prefix)).await?;
+    Ok(keys
+        .into_iter()
+        .filter_map(|key| {
+            let zone = key.strip_prefix(&prefix)?.strip_suffix(":SOA")?;
+            // skip the keys of views with a prefix when we're looking at the unprefixed keys
+            (!zone.contains(':')).then(|| zone.to_string())
+        })
+        .collect())
+}
+
+/// Returns the hashed owner name (the first label of the owner name) of the NSEC3 record that
+/// covers the given hash in the given zone, or `None` if the zone has no NSEC3 records.
+///
+/// The hashed owner names of all NSEC3 records of a zone are stored by the signer as a sorted set
+/// at `<zone>:NSEC3HASHES`, with all members having the score 0 so that they're sorted by their
+/// (lowercase) value.
+pub async fn get_covering_nsec3_hash(
+    con: &mut Connection,
+    zone: &Name,
+    hash: &str,
+    options: &KeyOptions,
+) -> PektinResult<Option<String>> {
+    let key = options.db_key(&format!("{}:NSEC3HASHES", zone.to_lowercase()));
+    // the covering record is the one with the greatest hash that is smaller than the given hash
+    let preceding: Vec<String> = redis::cmd("ZREVRANGEBYLEX")
+        .arg(&key)
+        .arg(format!("({}", hash))
+        .arg("-")
+        .arg("LIMIT")
+        .arg(0)
+        .arg(1)
+        .query_async(con)
+        .await?;
+    if let Some(covering) = preceding.into_iter().next() {
+        return Ok(Some(covering));
+    }
+    // if there is none, the chain wraps around and the last record covers the hash
+    let last: Vec<String> = con.zrange(&key, -1, -1).await?;
+    Ok(last.into_iter().next())
 }
 
 async fn get_definitive_or_wildcard_records(
     con: &mut Connection,
     definitive_key: &str,
     wildcard_key: &str,
-) -> PektinResult<QueryResponse> {
-    let res: Vec<Value> = con.get(vec![definitive_key, wildcard_key]).await?;
-    if res.len() != 2 {
+    options: &KeyOptions,
+) -> PektinResult<(QueryResponse, Option<String>)> {
+    let variants = &options.variants;
+    let mut keys = Vec::with_capacity(2 * (variants.len() + 1));
+    for variant in variants {
+        keys.push(options.db_key(&format!("{}@{}", definitive_key, variant)));
+        keys.push(options.db_key(&format!("{}@{}", wildcard_key, variant)));
+    }
+    keys.push(options.db_key(definitive_key));
+    keys.push(options.db_key(wildcard_key));
+
+    let res: Vec<Value> = con.get(keys).await?;
+    if res.len() != 2 * (variants.len() + 1) {
         return Err(PektinError::InvalidDbData);
     }
 
-    let string_res = (
-        String::from_redis_value(&res[0]),
-        String::from_redis_value(&res[1]),
-    );
-
-    Ok(match string_res {
-        (Ok(def), Ok(wild)) => QueryResponse::Both {
-            definitive: DbEntry::deserialize_from_db(&definitive_key, &def)?,
-            wildcard: DbEntry::deserialize_from_db(&wildcard_key, &wild)?,
-        },
-        (Ok(def), Err(_)) => {
-            if !matches!(res[1], Value::Nil) {
-                return Err(PektinError::WickedDbValue);
-            }
-            QueryResponse::Definitive(DbEntry::deserialize_from_db(&definitive_key, &def)?)
-        }
-        (Err(_), Ok(wild)) => {
-            if !matches!(res[0], Value::Nil) {
-                return Err(PektinError::WickedDbValue);
-            }
-            QueryResponse::Wildcard(DbEntry::deserialize_from_db(&wildcard_key, &wild)?)
+    // the first variant that has an entry wins, the default entry comes last
+    let mut definitive = None;
+    let mut wildcard = None;
+    let all_variants = variants.iter().map(Some).chain(std::iter::once(None));
+    for (values, variant) in res.chunks(2).zip(all_variants) {
+        if definitive.is_none() {
+            definitive = deserialize_db_value(definitive_key, &values[0])?.map(|e| (e, variant));
         }
-        (Err(_), Err(_)) => {
-            if !matches!(res[0], Value::Nil) || !matches!(res[1], Value::Nil) {
-                return Err(PektinError::WickedDbValue);
-            }
-            QueryResponse::Empty
+        if wildcard.is_none() {
+            wildcard = deserialize_db_value(wildcard_key, &values[1])?.map(|e| (e, variant));
         }
+    }
+
+    Ok(match (definitive, wildcard) {
+        (Some((definitive, variant)), Some((wildcard, _))) => (
+            QueryResponse::Both {
+                definitive,
+                wildcard,
+            },
+            variant.cloned(),
+        ),
+        (Some((def, variant)), None) => (QueryResponse::Definitive(def), variant.cloned()),
+        (None, Some((wild, variant))) => (QueryResponse::Wildcard(wild), variant.cloned()),
+        (None, None) => (QueryResponse::Empty, None),
     })
 }
+
+/// Deserializes the value of the given db key, returning `None` if the key doesn't exist.
+fn deserialize_db_value(key: &str, value: &Value) -> PektinResult<Option<DbEntry>> {
+    if matches!(value, Value::Nil) {
+        return Ok(None);
+    }
+    let string = String::from_redis_value(value).map_err(|_| PektinError::WickedDbValue)?;
+    Ok(Some(DbEntry::deserialize_from_db(key, &string)?))
+}
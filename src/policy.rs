@@ -0,0 +1,196 @@
+use pektin_common::proto::rr::Record;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Deserialize;
+
+/// Determines which records of an RRset are served in which order.
+///
+/// The policy of the RRset stored at `<key>` is stored as JSON at `<key>:POLICY`, e.g.
+/// `{ "policy": "weighted", "weights": [3, 1, 1], "count": 1 }`. RRsets without a policy are
+/// served in the stored order.
+///
+/// Since validators verify the signature over the canonically ordered RRset, reordering the
+/// records never invalidates DNSSEC. A subset of the records doesn't match the RRSIG records
+/// though, so subsets can't be served in signed zones: policies that only serve some of the
+/// records return the complete RRset if it has RRSIG records, with the selected records first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum AnswerPolicy {
+    /// Serve all records in the stored order.
+    #[default]
+    Stored,
+    /// Serve all records in a random order (round robin).
+    Shuffle,
+    /// Serve `count` records, chosen randomly with the given weights (one per record, in the
+    /// stored order). Records without a weight have a weight of 1, records with a weight of 0 are
+    /// never chosen. If no record can be chosen, e.g. because `count` is 0 or all weights are 0,
+    /// all records are served in the stored order.
+    Weighted { weights: Vec<u32>, count: usize },
+    /// Serve the first healthy record in the stored order.
+    FirstHealthy,
+}
+
+impl AnswerPolicy {
+    /// Applies the policy to the records of an RRset.
+    ///
+    /// If `complete_rrset` is true, which it must be for RRsets with RRSIG records, the records are
+    /// only reordered, never removed.
+    pub fn apply(
+        &self,
+        records: &mut Vec<Record>,
+        is_healthy: impl Fn(&Record) -> bool,
+        complete_rrset: bool,
+    ) {
+        match self {
+            Self::Stored => {}
+            Self::Shuffle => records.shuffle(&mut rand::thread_rng()),
+            Self::Weighted { weights, count } => {
+                let mut rng = rand::thread_rng();
+                let mut remaining: Vec<_> = records
+                    .drain(..)
+                    .enumerate()
+                    .map(|(index, record)| (weights.get(index).copied().unwrap_or(1), record))
+                    .collect();
+                // weighted random sampling without replacement
+                while records.len() < *count {
+                    let total: u64 = remaining.iter().map(|(weight, _)| *weight as u64).sum();
+                    if total == 0 {
+                        break;
+                    }
+                    let mut target = rng.gen_range(0..total);
+                    let index = remaining
+                        .iter()
+                        .position(|(weight, _)| match target.checked_sub(*weight as u64) {
+                            Some(rest) => {
+                                target = rest;
+                                false
+                            }
+                            None => true,
+                        })
+                        .expect("target is smaller than the sum of the weights");
+                    records.push(remaining.remove(index).1);
+                }
+                // removing the chosen records keeps the stored order of the remaining ones
+                if complete_rrset || records.is_empty() {
+                    records.extend(remaining.into_iter().map(|(_, record)| record));
+                }
+            }
+            Self::FirstHealthy => {
+                // a stable sort keeps the stored order among the healthy and unhealthy records
+                records.sort_by_key(|record| !is_healthy(record));
+                if !complete_rrset {
+                    records.truncate(1);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use pektin_common::proto::rr::{Name, RData};
+
+    use super::*;
+
+    fn records(count: u8) -> Vec<Record> {
+        (1..=count)
+            .map(|i| Record::from_rdata(Name::root(), 300, RData::A(Ipv4Addr::new(192, 0, 2, i))))
+            .collect()
+    }
+
+    fn healthy(_: &Record) -> bool {
+        true
+    }
+
+    #[test]
+    fn stored_keeps_the_order() {
+        let mut rrset = records(3);
+        AnswerPolicy::Stored.apply(&mut rrset, healthy, false);
+        assert_eq!(rrset, records(3));
+    }
+
+    #[test]
+    fn shuffle_keeps_all_records() {
+        let mut rrset = records(5);
+        AnswerPolicy::Shuffle.apply(&mut rrset, healthy, false);
+        assert_eq!(rrset.len(), 5);
+        for record in records(5) {
+            assert!(rrset.contains(&record));
+        }
+    }
+
+    #[test]
+    fn weighted_never_chooses_zero_weights() {
+        let policy = AnswerPolicy::Weighted {
+            weights: vec![0, 5, 0],
+            count: 1,
+        };
+        for _ in 0..20 {
+            let mut rrset = records(3);
+            policy.apply(&mut rrset, healthy, false);
+            assert_eq!(rrset, vec![records(3).remove(1)]);
+        }
+    }
+
+    #[test]
+    fn weighted_returns_the_complete_signed_rrset() {
+        let policy = AnswerPolicy::Weighted {
+            weights: vec![0, 5, 0],
+            count: 1,
+        };
+        let mut rrset = records(3);
+        policy.apply(&mut rrset, healthy, true);
+        let expected = records(3);
+        assert_eq!(
+            rrset,
+            vec![
+                expected[1].clone(),
+                expected[0].clone(),
+                expected[2].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn weighted_falls_back_to_the_stored_order() {
+        let zero_count = AnswerPolicy::Weighted {
+            weights: vec![],
+            count: 0,
+        };
+        let mut rrset = records(3);
+        zero_count.apply(&mut rrset, healthy, false);
+        assert_eq!(rrset, records(3));
+
+        let zero_weights = AnswerPolicy::Weighted {
+            weights: vec![0, 0, 0],
+            count: 2,
+        };
+        let mut rrset = records(3);
+        zero_weights.apply(&mut rrset, healthy, false);
+        assert_eq!(rrset, records(3));
+    }
+
+    #[test]
+    fn first_healthy_skips_unhealthy_records() {
+        let unhealthy = records(2);
+        let is_healthy = |record: &Record| !unhealthy.contains(record);
+
+        let mut rrset = records(3);
+        AnswerPolicy::FirstHealthy.apply(&mut rrset, is_healthy, false);
+        assert_eq!(rrset, vec![records(3).remove(2)]);
+
+        let mut rrset = records(3);
+        AnswerPolicy::FirstHealthy.apply(&mut rrset, is_healthy, true);
+        let expected = records(3);
+        assert_eq!(
+            rrset,
+            vec![
+                expected[2].clone(),
+                expected[0].clone(),
+                expected[1].clone()
+            ]
+        );
+    }
+}
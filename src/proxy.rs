@@ -0,0 +1,238 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use ipnet::IpNet;
+use log::warn;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+use crate::{canonical_ip, PektinError, PektinResult};
+
+/// The signature every PROXY protocol v2 header starts with.
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The length of the fixed part of the header: the signature, the version and command, the address
+/// family and protocol, and the length of the rest of the header.
+const FIXED_LEN: usize = 16;
+
+/// A PROXY protocol v2 header, which a load balancer puts in front of the data it forwards to tell
+/// us the address of the actual client (see
+/// <https://www.haproxy.org/download/2.6/doc/proxy-protocol.txt>).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// `None` for connections the load balancer makes on its own behalf, e.g. for health checks,
+    /// or if it doesn't know the client address.
+    pub source: Option<SocketAddr>,
+    /// The length of the whole header in bytes.
+    pub len: usize,
+}
+
+impl ProxyHeader {
+    /// Parses the header at the start of the given bytes.
+    pub fn parse(bytes: &[u8]) -> PektinResult<Self> {
+        let invalid = PektinError::InvalidProxyHeader;
+        if bytes.len() < FIXED_LEN || !bytes.starts_with(SIGNATURE) {
+            return Err(invalid("missing signature"));
+        }
+        let len = FIXED_LEN + u16::from_be_bytes([bytes[14], bytes[15]]) as usize;
+        let addresses = bytes
+            .get(FIXED_LEN..len)
+            .ok_or_else(|| invalid("header is truncated"))?;
+
+        if bytes[12] >> 4 != 2 {
+            return Err(invalid("unsupported version"));
+        }
+        let source = match bytes[12] & 0x0f {
+            // LOCAL
+            0 => None,
+            // PROXY
+            1 => match bytes[13] >> 4 {
+                1 if addresses.len() >= 12 => {
+                    let mut ip = [0; 4];
+                    ip.copy_from_slice(&addresses[0..4]);
+                    let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+                    Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+                }
+                2 if addresses.len() >= 36 => {
+                    let mut ip = [0; 16];
+                    ip.copy_from_slice(&addresses[0..16]);
+                    let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+                    Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+                }
+                1 | 2 => return Err(invalid("address block is too short")),
+                // AF_UNSPEC and AF_UNIX carry no usable address
+                _ => None,
+            },
+            _ => return Err(invalid("unsupported command")),
+        };
+        Ok(Self { source, len })
+    }
+
+    /// Reads the header from the start of a stream, leaving the stream right after it.
+    pub async fn read_from<R: AsyncRead + Unpin>(stream: &mut R) -> PektinResult<Self> {
+        let mut header = vec![0; FIXED_LEN];
+        stream.read_exact(&mut header).await?;
+        if !header.starts_with(SIGNATURE) {
+            return Err(PektinError::InvalidProxyHeader("missing signature"));
+        }
+        let rest_len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        header.resize(FIXED_LEN + rest_len, 0);
+        stream.read_exact(&mut header[FIXED_LEN..]).await?;
+        Self::parse(&header)
+    }
+}
+
+/// Returns whether the peer is a load balancer that we expect PROXY protocol headers from.
+pub fn is_trusted(peer: IpAddr, trusted_networks: &[IpNet]) -> bool {
+    let peer = canonical_ip(peer);
+    trusted_networks.iter().any(|net| net.contains(&peer))
+}
+
+/// Returns the address of the client behind a connection from the given peer.
+///
+/// Connections from trusted load balancers start with a PROXY protocol header, which is read from
+/// the stream. If it can't be read within `header_timeout`, `None` is returned and the connection
+/// should be closed.
+pub async fn client_addr<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    peer: SocketAddr,
+    trusted_networks: &[IpNet],
+    header_timeout: Duration,
+) -> Option<SocketAddr> {
+    if !is_trusted(peer.ip(), trusted_networks) {
+        return Some(peer);
+    }
+    match timeout(header_timeout, ProxyHeader::read_from(stream)).await {
+        Ok(Ok(header)) => Some(header.source.unwrap_or(peer)),
+        Ok(Err(e)) => {
+            warn!("Could not read PROXY protocol header from {}: {}", peer, e);
+            None
+        }
+        Err(_) => {
+            warn!("Timed out reading PROXY protocol header from {}", peer);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a header with the given version and command, address family and protocol, and
+    /// address block.
+    fn header(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.push(version_command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn parses_ipv4_source() {
+        // source 192.0.2.1:5353, destination 198.51.100.1:53
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0x14, 0xe9, 0, 53];
+        let mut bytes = header(0x21, 0x12, &addresses);
+        // the data after the header isn't part of it
+        bytes.extend_from_slice(b"query");
+        assert_eq!(
+            ProxyHeader::parse(&bytes).unwrap(),
+            ProxyHeader {
+                source: Some("192.0.2.1:5353".parse().unwrap()),
+                len: 28,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_ipv6_source() {
+        let mut addresses = vec![0; 36];
+        addresses[0..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses[16..32].copy_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses[32..34].copy_from_slice(&5353u16.to_be_bytes());
+        addresses[34..36].copy_from_slice(&53u16.to_be_bytes());
+        let header = ProxyHeader::parse(&header(0x21, 0x22, &addresses)).unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:5353".parse().unwrap()));
+        assert_eq!(header.len, 52);
+    }
+
+    #[test]
+    fn local_and_unspec_have_no_source() {
+        assert_eq!(
+            ProxyHeader::parse(&header(0x20, 0x00, &[])).unwrap(),
+            ProxyHeader {
+                source: None,
+                len: 16,
+            }
+        );
+        let header = ProxyHeader::parse(&header(0x21, 0x00, &[0; 4])).unwrap();
+        assert_eq!(header.source, None);
+        assert_eq!(header.len, 20);
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        // missing signature
+        let mut bytes = header(0x21, 0x12, &[0; 12]);
+        bytes[0] = b'x';
+        assert!(ProxyHeader::parse(&bytes).is_err());
+        assert!(ProxyHeader::parse(&SIGNATURE[..]).is_err());
+        // truncated
+        let bytes = header(0x21, 0x12, &[0; 12]);
+        assert!(ProxyHeader::parse(&bytes[..bytes.len() - 1]).is_err());
+        // version 1 and an unknown command
+        assert!(ProxyHeader::parse(&header(0x11, 0x12, &[0; 12])).is_err());
+        assert!(ProxyHeader::parse(&header(0x22, 0x12, &[0; 12])).is_err());
+        // the address block is too short for the address family
+        assert!(ProxyHeader::parse(&header(0x21, 0x12, &[0; 8])).is_err());
+        assert!(ProxyHeader::parse(&header(0x21, 0x22, &[0; 12])).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_only_the_header() {
+        let mut bytes = header(0x21, 0x12, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 53]);
+        bytes.extend_from_slice(b"query");
+        let mut stream = &bytes[..];
+        let header = ProxyHeader::read_from(&mut stream).await.unwrap();
+        assert_eq!(header.source, Some("192.0.2.1:1".parse().unwrap()));
+        assert_eq!(stream, b"query");
+    }
+
+    #[tokio::test]
+    async fn reads_the_header_only_from_trusted_peers() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let header_timeout = Duration::from_secs(1);
+        let mut bytes = header(0x21, 0x12, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 53]);
+        bytes.extend_from_slice(b"query");
+
+        let mut stream = &bytes[..];
+        let peer = "10.0.0.1:4242".parse().unwrap();
+        let client = client_addr(&mut stream, peer, &trusted, header_timeout).await;
+        assert_eq!(client, Some("192.0.2.1:1".parse().unwrap()));
+        assert_eq!(stream, b"query");
+
+        let mut stream = &bytes[..];
+        let peer = "198.51.100.1:4242".parse().unwrap();
+        let client = client_addr(&mut stream, peer, &trusted, header_timeout).await;
+        assert_eq!(client, Some(peer));
+        assert_eq!(stream, &bytes[..]);
+
+        // LOCAL connections of the load balancer itself
+        let bytes = header(0x20, 0x00, &[]);
+        let peer = "10.0.0.1:4242".parse().unwrap();
+        let client = client_addr(&mut &bytes[..], peer, &trusted, header_timeout).await;
+        assert_eq!(client, Some(peer));
+
+        // trusted peers must send a header
+        let client = client_addr(
+            &mut &b"GET / HTTP/1.1\r\n\r\n"[..],
+            peer,
+            &trusted,
+            header_timeout,
+        )
+        .await;
+        assert_eq!(client, None);
+    }
+}
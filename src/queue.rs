@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+
+/// A bounded queue that drops its oldest item when a new one is pushed while it's full.
+///
+/// Under overload, the oldest queued requests are the ones whose clients are most likely to have
+/// given up on them already.
+pub struct DropOldestQueue<T> {
+    capacity: usize,
+    items: Mutex<VecDeque<T>>,
+    available: Notify,
+}
+
+impl<T> DropOldestQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            items: Mutex::new(VecDeque::new()),
+            available: Notify::new(),
+        }
+    }
+
+    /// Appends an item, returning the item that was dropped to make room for it, if any.
+    pub fn push(&self, item: T) -> Option<T> {
+        let dropped = {
+            let mut items = self.items.lock();
+            let dropped = if items.len() >= self.capacity {
+                items.pop_front()
+            } else {
+                None
+            };
+            items.push_back(item);
+            dropped
+        };
+        self.available.notify_one();
+        dropped
+    }
+
+    /// Removes the oldest item, waiting for one if the queue is empty.
+    pub async fn pop(&self) -> T {
+        loop {
+            if let Some(item) = self.items.lock().pop_front() {
+                return item;
+            }
+            self.available.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn pops_in_fifo_order() {
+        let queue = DropOldestQueue::new(3);
+        assert_eq!(queue.push(1), None);
+        assert_eq!(queue.push(2), None);
+        assert_eq!(queue.pop().await, 1);
+        assert_eq!(queue.pop().await, 2);
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_item_when_full() {
+        let queue = DropOldestQueue::new(2);
+        assert_eq!(queue.push(1), None);
+        assert_eq!(queue.push(2), None);
+        assert_eq!(queue.push(3), Some(1));
+        assert_eq!(queue.pop().await, 2);
+        assert_eq!(queue.pop().await, 3);
+    }
+
+    #[tokio::test]
+    async fn capacity_is_at_least_one() {
+        let queue = DropOldestQueue::new(0);
+        assert_eq!(queue.push(1), None);
+        assert_eq!(queue.push(2), Some(1));
+        assert_eq!(queue.pop().await, 2);
+    }
+
+    #[tokio::test]
+    async fn pop_waits_for_a_push() {
+        let queue = Arc::new(DropOldestQueue::new(1));
+        let timeout = Duration::from_millis(10);
+        assert!(tokio::time::timeout(timeout, queue.pop()).await.is_err());
+
+        let popper = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop().await }
+        });
+        queue.push(1);
+        assert_eq!(popper.await.unwrap(), 1);
+    }
+}
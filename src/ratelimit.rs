@@ -0,0 +1,38 @@
+use std::time::Instant;
+
+use parking_lot::Mutex;
+
+/// A token bucket that allows `rate` events per second on average and bursts of up to `burst`
+/// events.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    /// The number of available tokens and when it was last updated.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            state: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    /// Takes a token from the bucket, returning false if there is none.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock();
+        let (tokens, last_update) = &mut *state;
+        let now = Instant::now();
+        *tokens =
+            (*tokens + now.duration_since(*last_update).as_secs_f64() * self.rate).min(self.burst);
+        *last_update = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
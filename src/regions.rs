@@ -0,0 +1,115 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use ipnet::IpNet;
+
+use crate::{PektinError, PektinResult};
+
+/// Maps client networks to named regions.
+///
+/// The region of a client selects which variant of an RRset is served to it (see
+/// [`crate::persistence::KeyOptions::variants`]).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegionMap {
+    /// Sorted so that the most specific networks come first.
+    networks: Vec<(IpNet, String)>,
+}
+
+impl RegionMap {
+    /// Loads the region map from a file.
+    ///
+    /// Each line of the file contains a network in CIDR notation and the name of the region it
+    /// belongs to, separated by whitespace. Empty lines and lines starting with `#` are ignored.
+    ///
+    /// ```text
+    /// # internal networks
+    /// 10.0.0.0/8       internal
+    /// 2001:db8::/32    eu-central
+    /// ```
+    pub fn from_file(path: impl AsRef<Path>) -> PektinResult<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parses the region map from the contents of a region file (see [`RegionMap::from_file`]).
+    pub fn parse(contents: &str) -> PektinResult<Self> {
+        let mut networks = contents
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(line_number, line)| {
+                let invalid_line = || {
+                    PektinError::InvalidConfig(format!(
+                        "invalid line {} in region file",
+                        line_number
+                    ))
+                };
+                let mut parts = line.split_whitespace();
+                let network = parts
+                    .next()
+                    .and_then(|net| net.parse::<IpNet>().ok())
+                    .ok_or_else(invalid_line)?;
+                let region = parts.next().ok_or_else(invalid_line)?;
+                if parts.next().is_some() {
+                    return Err(invalid_line());
+                }
+                Ok((network.trunc(), region.to_string()))
+            })
+            .collect::<PektinResult<Vec<_>>>()?;
+        // the - makes it sort the networks with the longest prefix first
+        networks.sort_by_key(|(net, _)| -(net.prefix_len() as i16));
+        Ok(Self { networks })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    /// Returns the region of the most specific network containing the given address.
+    pub fn region_of(&self, address: IpAddr) -> Option<&str> {
+        let address = crate::canonical_ip(address);
+        self.networks
+            .iter()
+            .find(|(net, _)| net.contains(&address))
+            .map(|(_, region)| region.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_specific_network_wins() {
+        let regions = RegionMap::parse(
+            "# comment\n\n10.0.0.0/8 internal\n  10.1.2.3/16   office  \n2001:db8::/32 eu\n",
+        )
+        .unwrap();
+        assert_eq!(
+            regions.region_of("10.1.0.1".parse().unwrap()),
+            Some("office")
+        );
+        assert_eq!(
+            regions.region_of("10.2.0.1".parse().unwrap()),
+            Some("internal")
+        );
+        assert_eq!(
+            regions.region_of("2001:db8::1".parse().unwrap()),
+            Some("eu")
+        );
+        assert_eq!(regions.region_of("192.0.2.1".parse().unwrap()), None);
+        // IPv4-mapped addresses are matched against the IPv4 networks
+        assert_eq!(
+            regions.region_of("::ffff:10.2.0.1".parse().unwrap()),
+            Some("internal")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        assert!(RegionMap::parse("10.0.0.0/8").is_err());
+        assert!(RegionMap::parse("10.0.0.0/33 internal").is_err());
+        assert!(RegionMap::parse("10.0.0.0/8 internal extra").is_err());
+        assert!(RegionMap::parse("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+use log::info;
+use parking_lot::Mutex;
+use pektin_common::proto::op::{Message, ResponseCode};
+
+use crate::canonical_ip;
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+/// How often buckets that are full again are removed.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The buckets of each client network and response class, and when they were last cleaned up.
+type Buckets = (HashMap<(IpNet, ResponseClass), Bucket>, Instant);
+
+/// The classes of responses that are limited separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResponseClass {
+    /// Positive answers and NODATA responses.
+    Answer,
+    Nxdomain,
+    Error,
+}
+
+impl ResponseClass {
+    pub fn of(response: &Message) -> Self {
+        match response.response_code() {
+            ResponseCode::NoError => Self::Answer,
+            ResponseCode::NXDomain => Self::Nxdomain,
+            _ => Self::Error,
+        }
+    }
+}
+
+/// What to do with a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RrlAction {
+    Send,
+    Drop,
+    /// Send a truncated response without any records instead, so that legitimate clients can
+    /// retry over TCP.
+    Slip,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_update: Instant,
+    /// The number of responses dropped since the last slipped response.
+    dropped: u32,
+}
+
+/// Response rate limiting for UDP, similar to BIND's RRL.
+///
+/// Responses are limited per client network (e.g. /24 for IPv4 and /56 for IPv6) and
+/// [`ResponseClass`], so that we can't be used to reflect large amounts of traffic to a victim
+/// whose address is spoofed.
+pub struct ResponseRateLimiter {
+    /// Responses per second for each response class; 0 means unlimited.
+    answers_per_second: u32,
+    nxdomains_per_second: u32,
+    errors_per_second: u32,
+    /// How many seconds worth of responses can be sent in a burst.
+    window_seconds: u32,
+    /// Every `slip`th dropped response is slipped instead; 0 means never.
+    slip: u32,
+    /// Only log which responses would be dropped.
+    log_only: bool,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    /// The maximum number of buckets, like BIND's `max-table-size`, so that a flood of queries
+    /// with spoofed source addresses can't use up our memory.
+    max_table_size: usize,
+    buckets: Mutex<Buckets>,
+}
+
+impl ResponseRateLimiter {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            answers_per_second: config.rrl_answers_per_second,
+            nxdomains_per_second: config.rrl_nxdomains_per_second,
+            errors_per_second: config.rrl_errors_per_second,
+            window_seconds: config.rrl_window_seconds.max(1),
+            slip: config.rrl_slip,
+            log_only: config.rrl_log_only,
+            ipv4_prefix: config.rrl_ipv4_prefix.min(32),
+            ipv6_prefix: config.rrl_ipv6_prefix.min(128),
+            max_table_size: config.rrl_max_table_size.max(1),
+            buckets: Mutex::new((HashMap::new(), Instant::now())),
+        }
+    }
+
+    /// Returns the rate and burst size for the given response class, or `None` if it's unlimited.
+    fn limits(&self, class: ResponseClass) -> Option<(f64, f64)> {
+        let rate = match class {
+            ResponseClass::Answer => self.answers_per_second,
+            ResponseClass::Nxdomain => self.nxdomains_per_second,
+            ResponseClass::Error => self.errors_per_second,
+        };
+        let burst = rate as u64 * self.window_seconds as u64;
+        (rate > 0).then_some((rate as f64, burst as f64))
+    }
+
+    /// Decides what to do with a response of the given class to the given client.
+    pub fn check(&self, client_ip: IpAddr, class: ResponseClass, metrics: &Metrics) -> RrlAction {
+        let (rate, burst) = match self.limits(class) {
+            Some(limits) => limits,
+            None => return RrlAction::Send,
+        };
+
+        let client_ip = canonical_ip(client_ip);
+        let network = match client_ip {
+            IpAddr::V4(_) => IpNet::new(client_ip, self.ipv4_prefix),
+            IpAddr::V6(_) => IpNet::new(client_ip, self.ipv6_prefix),
+        }
+        .map(|net| net.trunc())
+        .unwrap_or_else(|_| client_ip.into());
+
+        let now = Instant::now();
+        let mut guard = self.buckets.lock();
+        let (buckets, last_cleanup) = &mut *guard;
+        let key = (network, class);
+        let full = buckets.len() >= self.max_table_size && !buckets.contains_key(&key);
+        if full || now.duration_since(*last_cleanup) > CLEANUP_INTERVAL {
+            self.remove_full_buckets(buckets, now);
+            *last_cleanup = now;
+        }
+        if buckets.len() >= self.max_table_size && !buckets.contains_key(&key) {
+            evict_oldest_buckets(buckets, self.max_table_size);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            last_update: now,
+            dropped: 0,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.last_update).as_secs_f64() * rate)
+            .min(burst);
+        bucket.last_update = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return RrlAction::Send;
+        }
+
+        bucket.dropped += 1;
+        let action = if self.slip > 0 && bucket.dropped % self.slip == 0 {
+            metrics
+                .rrl_slipped_responses
+                .fetch_add(1, Ordering::Relaxed);
+            RrlAction::Slip
+        } else {
+            metrics
+                .rrl_dropped_responses
+                .fetch_add(1, Ordering::Relaxed);
+            RrlAction::Drop
+        };
+        if self.log_only {
+            info!("RRL would {:?} {:?} response to {}", action, class, network);
+            return RrlAction::Send;
+        }
+        action
+    }
+
+    /// Removes the buckets that are full again, since they behave exactly like new ones.
+    fn remove_full_buckets(
+        &self,
+        buckets: &mut HashMap<(IpNet, ResponseClass), Bucket>,
+        now: Instant,
+    ) {
+        buckets.retain(|(_, class), bucket| match self.limits(*class) {
+            Some((rate, burst)) => {
+                bucket.tokens + now.duration_since(bucket.last_update).as_secs_f64() * rate < burst
+            }
+            None => false,
+        });
+    }
+}
+
+/// Removes the least recently used tenth of the buckets of a full table.
+///
+/// Evicting a batch at once keeps the cost of a full table low when every query comes from a new
+/// network, which is exactly what happens during a flood with spoofed source addresses.
+fn evict_oldest_buckets(buckets: &mut HashMap<(IpNet, ResponseClass), Bucket>, max_len: usize) {
+    let mut last_updates: Vec<_> = buckets.values().map(|b| b.last_update).collect();
+    let evicted = (max_len / 10).max(buckets.len() + 1 - max_len);
+    if evicted >= last_updates.len() {
+        buckets.clear();
+        return;
+    }
+    let (_, &mut threshold, _) = last_updates.select_nth_unstable(evicted - 1);
+    let mut remaining = evicted;
+    buckets.retain(|_, bucket| {
+        if remaining > 0 && bucket.last_update <= threshold {
+            remaining -= 1;
+            false
+        } else {
+            true
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(answers_per_second: u32, slip: u32) -> ResponseRateLimiter {
+        ResponseRateLimiter {
+            answers_per_second,
+            nxdomains_per_second: 0,
+            errors_per_second: 0,
+            window_seconds: 2,
+            slip,
+            log_only: false,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            max_table_size: 100,
+            buckets: Mutex::new((HashMap::new(), Instant::now())),
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn unlimited_classes_are_always_sent() {
+        let rrl = limiter(1, 2);
+        let metrics = Metrics::default();
+        for _ in 0..10 {
+            let action = rrl.check(ip("192.0.2.1"), ResponseClass::Nxdomain, &metrics);
+            assert_eq!(action, RrlAction::Send);
+        }
+        assert!(rrl.buckets.lock().0.is_empty());
+    }
+
+    #[test]
+    fn slips_every_nth_dropped_response() {
+        let rrl = limiter(1, 2);
+        let metrics = Metrics::default();
+        let actions: Vec<_> = (0..6)
+            .map(|_| rrl.check(ip("192.0.2.1"), ResponseClass::Answer, &metrics))
+            .collect();
+        use RrlAction::*;
+        // the burst is the rate times the window
+        assert_eq!(actions, [Send, Send, Drop, Slip, Drop, Slip]);
+        assert_eq!(metrics.rrl_dropped_responses.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.rrl_slipped_responses.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn never_slips_with_slip_0() {
+        let rrl = limiter(1, 0);
+        let metrics = Metrics::default();
+        let actions: Vec<_> = (0..4)
+            .map(|_| rrl.check(ip("192.0.2.1"), ResponseClass::Answer, &metrics))
+            .collect();
+        use RrlAction::*;
+        assert_eq!(actions, [Send, Send, Drop, Drop]);
+    }
+
+    #[test]
+    fn limits_client_networks() {
+        let rrl = limiter(1, 0);
+        let metrics = Metrics::default();
+        rrl.check(ip("192.0.2.1"), ResponseClass::Answer, &metrics);
+        rrl.check(ip("::ffff:192.0.2.2"), ResponseClass::Answer, &metrics);
+        assert_eq!(
+            rrl.check(ip("192.0.2.3"), ResponseClass::Answer, &metrics),
+            RrlAction::Drop
+        );
+        assert_eq!(
+            rrl.check(ip("192.0.3.1"), ResponseClass::Answer, &metrics),
+            RrlAction::Send
+        );
+    }
+
+    #[test]
+    fn log_only_sends_everything() {
+        let mut rrl = limiter(1, 0);
+        rrl.log_only = true;
+        let metrics = Metrics::default();
+        for _ in 0..4 {
+            let action = rrl.check(ip("192.0.2.1"), ResponseClass::Answer, &metrics);
+            assert_eq!(action, RrlAction::Send);
+        }
+        assert_eq!(metrics.rrl_dropped_responses.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn table_size_is_limited() {
+        let rrl = limiter(1, 0);
+        let metrics = Metrics::default();
+        for i in 0..=255 {
+            for _ in 0..3 {
+                rrl.check(IpAddr::from([10, 0, i, 1]), ResponseClass::Answer, &metrics);
+            }
+        }
+        assert!(rrl.buckets.lock().0.len() <= 100);
+        // the most recent client is still limited
+        assert_eq!(
+            rrl.check(ip("10.0.255.1"), ResponseClass::Answer, &metrics),
+            RrlAction::Drop
+        );
+    }
+
+    #[test]
+    fn burst_does_not_overflow() {
+        let mut rrl = limiter(u32::MAX, 0);
+        rrl.window_seconds = u32::MAX;
+        let (_, burst) = rrl.limits(ResponseClass::Answer).unwrap();
+        assert_eq!(burst, u32::MAX as f64 * u32::MAX as f64);
+    }
+}
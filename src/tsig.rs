@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use data_encoding::BASE64;
+use pektin_common::proto::op::Message;
+use pektin_common::proto::rr::dnssec::rdata::tsig::{
+    make_tsig_record, message_tbs, signed_bitmessage_to_buf, TsigAlgorithm, TSIG,
+};
+use pektin_common::proto::rr::dnssec::rdata::DNSSECRData;
+use pektin_common::proto::rr::{Name, RData, RecordType};
+use serde::Deserialize;
+
+use crate::{PektinError, PektinResult};
+
+/// The fudge we use for signed responses (see RFC 8945, section 5.2.3).
+const FUDGE_SECONDS: u16 = 300;
+
+// TSIG error codes (see RFC 8945, section 3)
+const BADSIG: u16 = 16;
+const BADKEY: u16 = 17;
+const BADTIME: u16 = 18;
+
+#[derive(Deserialize)]
+struct TsigKeyConfig {
+    name: String,
+    algorithm: String,
+    /// The base64-encoded secret.
+    secret: String,
+}
+
+struct TsigKey {
+    algorithm: TsigAlgorithm,
+    secret: Vec<u8>,
+}
+
+/// The keys that clients can use to sign their requests with TSIG (see RFC 8945).
+#[derive(Default)]
+pub struct TsigKeys {
+    /// Indexed by the lowercased key name.
+    keys: HashMap<Name, TsigKey>,
+}
+
+/// A valid TSIG signature of a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedTsig {
+    pub key_name: Name,
+    mac: Vec<u8>,
+}
+
+/// Why the TSIG signature of a request couldn't be verified.
+///
+/// The response to such a request must have the response code
+/// [`ResponseCode::NotAuth`](pektin_common::proto::op::ResponseCode::NotAuth) and should include
+/// a TSIG record describing the failure (see [`TsigKeys::add_failure`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TsigFailure {
+    key_name: Name,
+    algorithm: TsigAlgorithm,
+    error: u16,
+    /// Only set for BADTIME, where the MAC itself was valid and the response is signed.
+    request_mac: Option<Vec<u8>>,
+}
+
+impl TsigKeys {
+    /// Loads the TSIG keys from a JSON file of the following form.
+    ///
+    /// ```json
+    /// [{ "name": "internal.", "algorithm": "hmac-sha256", "secret": "<base64>" }]
+    /// ```
+    ///
+    /// The supported algorithms are `hmac-sha256`, `hmac-sha384` and `hmac-sha512`.
+    pub fn from_file(path: impl AsRef<Path>) -> PektinResult<Self> {
+        let configs: Vec<TsigKeyConfig> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let keys = configs
+            .into_iter()
+            .map(|config| {
+                let name = Name::from_utf8(&config.name).map_err(|_| {
+                    PektinError::InvalidConfig(format!("invalid TSIG key name {}", config.name))
+                })?;
+                let algorithm = TsigAlgorithm::from_name(
+                    Name::from_ascii(&config.algorithm).map_err(|_| {
+                        PektinError::InvalidConfig(format!(
+                            "invalid TSIG algorithm {}",
+                            config.algorithm
+                        ))
+                    })?,
+                );
+                if !algorithm.supported() {
+                    return Err(PektinError::InvalidConfig(format!(
+                        "unsupported TSIG algorithm {}",
+                        config.algorithm
+                    )));
+                }
+                let secret = BASE64.decode(config.secret.as_bytes()).map_err(|_| {
+                    PektinError::InvalidConfig(format!("invalid secret for TSIG key {}", name))
+                })?;
+                Ok((name.to_lowercase(), TsigKey { algorithm, secret }))
+            })
+            .collect::<PektinResult<_>>()?;
+        Ok(Self { keys })
+    }
+
+    pub fn contains(&self, key_name: &Name) -> bool {
+        self.keys.contains_key(&key_name.to_lowercase())
+    }
+
+    /// Verifies the TSIG signature of the given request, if it has one.
+    ///
+    /// `raw_message` must be the request exactly as it was received.
+    pub fn verify(
+        &self,
+        message: &Message,
+        raw_message: &[u8],
+    ) -> Result<Option<VerifiedTsig>, Box<TsigFailure>> {
+        let record = match message
+            .signature()
+            .iter()
+            .find(|r| r.rr_type() == RecordType::TSIG)
+        {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let tsig = match record.data() {
+            Some(RData::DNSSEC(DNSSECRData::TSIG(tsig))) => tsig,
+            _ => return Ok(None),
+        };
+        let failure = |error, request_mac| {
+            Box::new(TsigFailure {
+                key_name: record.name().clone(),
+                algorithm: tsig.algorithm().clone(),
+                error,
+                request_mac,
+            })
+        };
+
+        let key = match self.keys.get(&record.name().to_lowercase()) {
+            Some(key) if &key.algorithm == tsig.algorithm() => key,
+            _ => return Err(failure(BADKEY, None)),
+        };
+        let (tbs, _) =
+            signed_bitmessage_to_buf(None, raw_message, true).map_err(|_| failure(BADSIG, None))?;
+        key.algorithm
+            .verify_mac(&key.secret, &tbs, tsig.mac())
+            .map_err(|_| failure(BADSIG, None))?;
+
+        if now().abs_diff(tsig.time()) > tsig.fudge() as u64 {
+            return Err(failure(BADTIME, Some(tsig.mac().to_vec())));
+        }
+
+        Ok(Some(VerifiedTsig {
+            key_name: record.name().clone(),
+            mac: tsig.mac().to_vec(),
+        }))
+    }
+
+    /// Signs the response to a request with a verified TSIG signature.
+    ///
+    /// This must be the last modification of the response.
+    pub fn sign(&self, response: &mut Message, request_tsig: &VerifiedTsig) -> PektinResult<()> {
+        self.sign_with_error(response, request_tsig, 0, vec![])
+    }
+
+    /// Adds the TSIG record describing why the request's signature was invalid to the response.
+    ///
+    /// This must be the last modification of the response.
+    pub fn add_failure(&self, response: &mut Message, failure: TsigFailure) -> PektinResult<()> {
+        match failure.request_mac {
+            Some(mac) => {
+                // the server's current time, so that the client can detect the clock skew
+                let other = now().to_be_bytes()[2..].to_vec();
+                let request_tsig = VerifiedTsig {
+                    key_name: failure.key_name,
+                    mac,
+                };
+                self.sign_with_error(response, &request_tsig, failure.error, other)
+            }
+            None => {
+                let tsig = TSIG::new(
+                    failure.algorithm,
+                    now(),
+                    FUDGE_SECONDS,
+                    vec![],
+                    response.id(),
+                    failure.error,
+                    vec![],
+                );
+                response.add_tsig(make_tsig_record(failure.key_name, tsig));
+                Ok(())
+            }
+        }
+    }
+
+    fn sign_with_error(
+        &self,
+        response: &mut Message,
+        request_tsig: &VerifiedTsig,
+        error: u16,
+        other: Vec<u8>,
+    ) -> PektinResult<()> {
+        let key = self
+            .keys
+            .get(&request_tsig.key_name.to_lowercase())
+            .ok_or(PektinError::Bug(
+                "signing a response with an unknown TSIG key",
+            ))?;
+        let pre_tsig = TSIG::new(
+            key.algorithm.clone(),
+            now(),
+            FUDGE_SECONDS,
+            vec![],
+            response.id(),
+            error,
+            other,
+        );
+        let tbs = message_tbs(
+            Some(&request_tsig.mac[..]),
+            &*response,
+            &pre_tsig,
+            &request_tsig.key_name,
+        )?;
+        let mac = key.algorithm.mac_data(&key.secret, &tbs)?;
+        response.add_tsig(make_tsig_record(
+            request_tsig.key_name.clone(),
+            pre_tsig.set_mac(mac),
+        ));
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use pektin_common::proto::op::Query;
+    use pektin_common::proto::rr::dnssec::rdata::tsig;
+    use pektin_common::proto::serialize::binary::{
+        BinDecodable, BinDecoder, BinEncodable, BinEncoder,
+    };
+
+    use super::*;
+
+    const SECRET: &[u8] = b"not a very secret secret";
+
+    fn key_name() -> Name {
+        Name::from_ascii("internal.").unwrap()
+    }
+
+    fn tsig_keys() -> TsigKeys {
+        let key = TsigKey {
+            algorithm: TsigAlgorithm::HmacSha256,
+            secret: SECRET.to_vec(),
+        };
+        TsigKeys {
+            keys: HashMap::from([(key_name(), key)]),
+        }
+    }
+
+    /// Signs a request like a client would and returns it as it's received by the server.
+    fn signed_request(key_name: Name, secret: &[u8], time: u64) -> (Message, Vec<u8>) {
+        let mut request = Message::new();
+        request.set_id(4242).add_query(Query::query(
+            Name::from_ascii("example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let pre_tsig = TSIG::new(
+            TsigAlgorithm::HmacSha256,
+            time,
+            FUDGE_SECONDS,
+            vec![],
+            request.id(),
+            0,
+            vec![],
+        );
+        let tbs = message_tbs(None, &request, &pre_tsig, &key_name).unwrap();
+        let mac = TsigAlgorithm::HmacSha256.mac_data(secret, &tbs).unwrap();
+        request.add_tsig(make_tsig_record(key_name, pre_tsig.set_mac(mac)));
+
+        let raw = request.to_bytes().unwrap();
+        (Message::from_bytes(&raw).unwrap(), raw)
+    }
+
+    fn response_tsig(response: &Message) -> TSIG {
+        match response.signature()[0].data() {
+            Some(RData::DNSSEC(DNSSECRData::TSIG(tsig))) => tsig.clone(),
+            _ => panic!("the response has no TSIG record"),
+        }
+    }
+
+    /// Returns the error and other data of a TSIG record, which have no getters.
+    fn error_and_other(rdata: &TSIG) -> (u16, Vec<u8>) {
+        let mut buf = vec![];
+        tsig::emit(&mut BinEncoder::new(&mut buf), rdata).unwrap();
+        let mut decoder = BinDecoder::new(&buf);
+        Name::read(&mut decoder).unwrap();
+        // time signed, fudge, mac and original id
+        decoder.read_slice(8).unwrap();
+        let mac_len = decoder.read_u16().unwrap().unverified();
+        decoder.read_slice(mac_len as usize + 2).unwrap();
+        let error = decoder.read_u16().unwrap().unverified();
+        let other_len = decoder.read_u16().unwrap().unverified();
+        let other = decoder.read_vec(other_len as usize).unwrap().unverified();
+        (error, other)
+    }
+
+    #[test]
+    fn signs_and_verifies() {
+        let keys = tsig_keys();
+        let (request, raw_request) = signed_request(key_name(), SECRET, now());
+        let verified = keys.verify(&request, &raw_request).unwrap().unwrap();
+        assert_eq!(verified.key_name, key_name());
+
+        let mut response = Message::new();
+        response.set_id(request.id());
+        keys.sign(&mut response, &verified).unwrap();
+
+        // the client verifies the response with the MAC of its request
+        let raw_response = response.to_bytes().unwrap();
+        let (tbs, record) =
+            signed_bitmessage_to_buf(Some(&verified.mac[..]), &raw_response, true).unwrap();
+        let tsig = response_tsig(&response);
+        assert_eq!(record.name(), &key_name());
+        assert_eq!(error_and_other(&tsig), (0, vec![]));
+        TsigAlgorithm::HmacSha256
+            .verify_mac(SECRET, &tbs, tsig.mac())
+            .unwrap();
+    }
+
+    #[test]
+    fn ignores_unsigned_requests() {
+        let mut request = Message::new();
+        request.add_query(Query::query(
+            Name::from_ascii("example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let raw_request = request.to_bytes().unwrap();
+        assert_eq!(tsig_keys().verify(&request, &raw_request), Ok(None));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let keys = tsig_keys();
+        let unknown = Name::from_ascii("external.").unwrap();
+        let (request, raw_request) = signed_request(unknown.clone(), SECRET, now());
+        let failure = keys.verify(&request, &raw_request).unwrap_err();
+        assert_eq!(failure.key_name, unknown);
+        assert_eq!(failure.error, BADKEY);
+        assert_eq!(failure.request_mac, None);
+
+        // BADKEY responses aren't signed
+        let mut response = Message::new();
+        keys.add_failure(&mut response, *failure).unwrap();
+        let tsig = response_tsig(&response);
+        assert_eq!(error_and_other(&tsig), (BADKEY, vec![]));
+        assert!(tsig.mac().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_signatures() {
+        let keys = tsig_keys();
+        let (request, raw_request) = signed_request(key_name(), b"the wrong secret", now());
+        let failure = keys.verify(&request, &raw_request).unwrap_err();
+        assert_eq!(failure.error, BADSIG);
+        assert_eq!(failure.request_mac, None);
+
+        // a request that was modified after it was signed (by setting the RD flag)
+        let (request, mut raw_request) = signed_request(key_name(), SECRET, now());
+        raw_request[2] ^= 1;
+        let failure = keys.verify(&request, &raw_request).unwrap_err();
+        assert_eq!(failure.error, BADSIG);
+    }
+
+    #[test]
+    fn rejects_requests_outside_the_fudge() {
+        let keys = tsig_keys();
+        for time in [
+            now() - FUDGE_SECONDS as u64 - 60,
+            now() + FUDGE_SECONDS as u64 + 60,
+        ] {
+            let (request, raw_request) = signed_request(key_name(), SECRET, time);
+            let failure = keys.verify(&request, &raw_request).unwrap_err();
+            assert_eq!(failure.error, BADTIME);
+            assert!(failure.request_mac.is_some());
+
+            // BADTIME responses are signed and contain the server's time
+            let mut response = Message::new();
+            keys.add_failure(&mut response, *failure).unwrap();
+            let tsig = response_tsig(&response);
+            let (error, other) = error_and_other(&tsig);
+            assert_eq!(error, BADTIME);
+            assert_eq!(other.len(), 6);
+            assert!(!tsig.mac().is_empty());
+        }
+
+        // within the fudge
+        let (request, raw_request) =
+            signed_request(key_name(), SECRET, now() - FUDGE_SECONDS as u64 + 60);
+        assert!(keys.verify(&request, &raw_request).unwrap().is_some());
+    }
+}
@@ -0,0 +1,297 @@
+use std::io::{self, IoSlice, IoSliceMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use nix::sys::socket::{
+    recvmmsg, recvmsg, sendmsg, setsockopt, sockopt, ControlMessage, ControlMessageOwned, MsgFlags,
+    MultiHeaders, RecvMsg, SockaddrStorage,
+};
+use parking_lot::Mutex;
+use tokio::io::Interest;
+
+/// The size of the receive buffer, which is the maximum payload size we advertise.
+const MAX_PAYLOAD: usize = 4096;
+
+/// The content of a received datagram, its source address and the local address it was received
+/// on.
+pub type Datagram = (Vec<u8>, SocketAddr, Option<PacketInfo>);
+
+/// The local address a datagram was received on, taken from its `IP_PKTINFO` or `IPV6_PKTINFO`
+/// control message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketInfo {
+    pub local_ip: IpAddr,
+    pub interface: u32,
+}
+
+/// A UDP socket that sends responses from the address the query was sent to.
+///
+/// If the socket is bound to an unspecified address like `::` on a host with multiple addresses,
+/// the kernel would otherwise choose the source address of responses itself, which breaks clients
+/// querying a secondary address or an anycast address.
+///
+/// Responses are sent one at a time, since they become ready at different times and `sendmmsg`
+/// would need a separate call for every source address anyway.
+pub struct UdpListener {
+    socket: tokio::net::UdpSocket,
+    /// The maximum number of datagrams received with a single `recvmmsg` call, or 1 to use
+    /// `recvmsg`.
+    batch_size: usize,
+    /// `MAX_PAYLOAD` bytes for each datagram of a batch, which are copied out after receiving.
+    batch_buffer: Mutex<Vec<u8>>,
+}
+
+impl UdpListener {
+    pub fn new(socket: std::net::UdpSocket, batch_size: usize) -> io::Result<Self> {
+        let fd = socket.as_raw_fd();
+        match socket.local_addr()? {
+            SocketAddr::V4(_) => setsockopt(fd, sockopt::Ipv4PacketInfo, &true)?,
+            // this also covers IPv4 datagrams received on dual-stack sockets
+            SocketAddr::V6(_) => setsockopt(fd, sockopt::Ipv6RecvPacketInfo, &true)?,
+        }
+        let batch_size = batch_size.max(1);
+        let batch_buffer = match batch_size {
+            1 => vec![],
+            _ => vec![0; batch_size * MAX_PAYLOAD],
+        };
+        Ok(Self {
+            socket: tokio::net::UdpSocket::from_std(socket)?,
+            batch_size,
+            batch_buffer: Mutex::new(batch_buffer),
+        })
+    }
+
+    /// Receives the datagrams that are waiting on the socket, at least one and at most the batch
+    /// size.
+    pub async fn recv(&self) -> io::Result<Vec<Datagram>> {
+        if self.batch_size > 1 {
+            return self.recv_batch().await;
+        }
+        let fd = self.socket.as_raw_fd();
+        let mut buffer = vec![0; MAX_PAYLOAD];
+        loop {
+            self.socket.readable().await?;
+            match self
+                .socket
+                .try_io(Interest::READABLE, || recv_with_pktinfo(fd, &mut buffer))
+            {
+                Ok((len, src_addr, pktinfo)) => {
+                    buffer.truncate(len);
+                    return Ok(vec![(buffer, src_addr, pktinfo)]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn recv_batch(&self) -> io::Result<Vec<Datagram>> {
+        let fd = self.socket.as_raw_fd();
+        loop {
+            self.socket.readable().await?;
+            let result = self.socket.try_io(Interest::READABLE, || {
+                let mut batch_buffer = self.batch_buffer.lock();
+                let mut buffers: Vec<_> = batch_buffer.chunks_mut(MAX_PAYLOAD).collect();
+                let received = recv_batch_with_pktinfo(fd, &mut buffers)?;
+                Ok(received
+                    .into_iter()
+                    .zip(buffers)
+                    .map(|((len, src_addr, pktinfo), buffer)| {
+                        (buffer[..len].to_vec(), src_addr, pktinfo)
+                    })
+                    .collect())
+            });
+            match result {
+                Ok(datagrams) => return Ok(datagrams),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Sends a datagram, using the local address from `pktinfo` as its source address if given.
+    pub async fn send(
+        &self,
+        bytes: &[u8],
+        dst_addr: SocketAddr,
+        pktinfo: Option<PacketInfo>,
+    ) -> io::Result<()> {
+        let fd = self.socket.as_raw_fd();
+        loop {
+            self.socket.writable().await?;
+            match self.socket.try_io(Interest::WRITABLE, || {
+                send_with_pktinfo(fd, bytes, dst_addr, pktinfo)
+            }) {
+                Ok(_) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn recv_with_pktinfo(
+    fd: RawFd,
+    buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<PacketInfo>)> {
+    // big enough for both kinds of control messages
+    let mut cmsg_buffer = nix::cmsg_space!(libc::in6_pktinfo);
+    let mut iov = [IoSliceMut::new(buffer)];
+    let msg = recvmsg::<SockaddrStorage>(fd, &mut iov, Some(&mut cmsg_buffer), MsgFlags::empty())?;
+    Ok((msg.bytes, source_addr(&msg)?, packet_info(&msg)))
+}
+
+/// Receives up to one datagram per buffer with a single system call.
+///
+/// Returns the length, source address and local address of each received datagram, in the order
+/// of the buffers they were written to.
+fn recv_batch_with_pktinfo(
+    fd: RawFd,
+    buffers: &mut [&mut [u8]],
+) -> io::Result<Vec<(usize, SocketAddr, Option<PacketInfo>)>> {
+    let mut headers = MultiHeaders::<SockaddrStorage>::preallocate(
+        buffers.len(),
+        Some(nix::cmsg_space!(libc::in6_pktinfo)),
+    );
+    let iovs: Vec<_> = buffers
+        .iter_mut()
+        .map(|buffer| [IoSliceMut::new(buffer)])
+        .collect();
+    // the socket is non-blocking, so this returns the datagrams that are already waiting
+    recvmmsg(fd, &mut headers, &iovs, MsgFlags::empty(), None)?
+        .map(|msg| Ok((msg.bytes, source_addr(&msg)?, packet_info(&msg))))
+        .collect()
+}
+
+fn packet_info(msg: &RecvMsg<'_, '_, SockaddrStorage>) -> Option<PacketInfo> {
+    msg.cmsgs().find_map(|cmsg| match cmsg {
+        ControlMessageOwned::Ipv4PacketInfo(info) => Some(PacketInfo {
+            local_ip: Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr)).into(),
+            interface: info.ipi_ifindex as u32,
+        }),
+        ControlMessageOwned::Ipv6PacketInfo(info) => Some(PacketInfo {
+            local_ip: Ipv6Addr::from(info.ipi6_addr.s6_addr).into(),
+            interface: info.ipi6_ifindex,
+        }),
+        _ => None,
+    })
+}
+
+fn source_addr(msg: &RecvMsg<'_, '_, SockaddrStorage>) -> io::Result<SocketAddr> {
+    msg.address
+        .and_then(|addr| {
+            addr.as_sockaddr_in()
+                .map(|addr| SocketAddr::V4(SocketAddrV4::from(*addr)))
+                .or_else(|| {
+                    addr.as_sockaddr_in6()
+                        .map(|addr| SocketAddr::V6(SocketAddrV6::from(*addr)))
+                })
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "datagram without source"))
+}
+
+fn send_with_pktinfo(
+    fd: RawFd,
+    bytes: &[u8],
+    dst_addr: SocketAddr,
+    pktinfo: Option<PacketInfo>,
+) -> io::Result<usize> {
+    let iov = [IoSlice::new(bytes)];
+    let dst_addr = SockaddrStorage::from(dst_addr);
+    let sent = match pktinfo {
+        Some(PacketInfo {
+            local_ip: IpAddr::V4(local_ip),
+            ..
+        }) => {
+            // the interface is left to the routing table, only the source address is set
+            let info = libc::in_pktinfo {
+                ipi_ifindex: 0,
+                ipi_spec_dst: libc::in_addr {
+                    s_addr: u32::from(local_ip).to_be(),
+                },
+                ipi_addr: libc::in_addr { s_addr: 0 },
+            };
+            let cmsgs = [ControlMessage::Ipv4PacketInfo(&info)];
+            sendmsg(fd, &iov, &cmsgs, MsgFlags::empty(), Some(&dst_addr))?
+        }
+        Some(PacketInfo {
+            local_ip: IpAddr::V6(local_ip),
+            interface,
+        }) => {
+            // the interface is needed for link-local addresses
+            let info = libc::in6_pktinfo {
+                ipi6_addr: libc::in6_addr {
+                    s6_addr: local_ip.octets(),
+                },
+                ipi6_ifindex: interface,
+            };
+            let cmsgs = [ControlMessage::Ipv6PacketInfo(&info)];
+            sendmsg(fd, &iov, &cmsgs, MsgFlags::empty(), Some(&dst_addr))?
+        }
+        None => sendmsg(fd, &iov, &[], MsgFlags::empty(), Some(&dst_addr))?,
+    };
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(batch_size: usize) -> (UdpListener, SocketAddr) {
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+        (UdpListener::new(socket, batch_size).unwrap(), addr)
+    }
+
+    #[tokio::test]
+    async fn receives_batches_with_pktinfo() {
+        let (listener, addr) = listener(2);
+        let client = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let client_addr = client.local_addr().unwrap();
+        for payload in [&b"first"[..], b"second", b"third"] {
+            client.send_to(payload, addr).await.unwrap();
+        }
+
+        let mut received = vec![];
+        while received.len() < 3 {
+            let datagrams = listener.recv().await.unwrap();
+            assert!((1..=2).contains(&datagrams.len()));
+            received.extend(datagrams);
+        }
+        let pktinfo = received[0].2.unwrap();
+        assert_eq!(pktinfo.local_ip, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(
+            received,
+            vec![
+                (b"first".to_vec(), client_addr, Some(pktinfo)),
+                (b"second".to_vec(), client_addr, Some(pktinfo)),
+                (b"third".to_vec(), client_addr, Some(pktinfo)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn receives_single_datagrams_and_responds_from_the_local_address() {
+        let (listener, addr) = listener(1);
+        let client = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        client.send_to(b"query", addr).await.unwrap();
+        client.send_to(b"another query", addr).await.unwrap();
+
+        let datagrams = listener.recv().await.unwrap();
+        assert_eq!(datagrams.len(), 1);
+        let (bytes, src_addr, pktinfo) = datagrams.into_iter().next().unwrap();
+        assert_eq!(bytes, b"query");
+        assert_eq!(src_addr, client.local_addr().unwrap());
+
+        listener.send(b"response", src_addr, pktinfo).await.unwrap();
+        let mut buf = [0; 512];
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"response");
+        assert_eq!(from, addr);
+    }
+}
@@ -0,0 +1,225 @@
+use std::net::IpAddr;
+use std::path::Path;
+
+use pektin_common::deadpool_redis::Pool;
+use pektin_common::proto::rr::Name;
+use serde::Deserialize;
+
+use crate::clients::ClientMatcher;
+use crate::config::Config;
+use crate::{PektinError, PektinResult};
+
+#[derive(Deserialize)]
+struct ViewsConfig {
+    views: Vec<ViewConfig>,
+    fallback: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ViewConfig {
+    name: String,
+    #[serde(default)]
+    networks: Vec<String>,
+    #[serde(default)]
+    tsig_keys: Vec<String>,
+    key_prefix: Option<String>,
+    db: Option<u8>,
+    dnssec_db: Option<u8>,
+}
+
+/// A set of clients that gets its own answers (split-horizon DNS).
+///
+/// The data of a view is either stored with a key prefix (see
+/// [`KeyOptions::prefix`](crate::persistence::KeyOptions::prefix)) or in separate dbs.
+pub struct View {
+    pub name: String,
+    clients: ClientMatcher,
+    pub key_prefix: Option<String>,
+    /// `None` if the view uses the default dbs.
+    pub db_pools: Option<(Pool, Pool)>,
+}
+
+impl View {
+    /// The default view, which uses the default dbs without a key prefix.
+    fn default_view() -> Self {
+        Self {
+            name: "default".into(),
+            clients: ClientMatcher::default(),
+            key_prefix: None,
+            db_pools: None,
+        }
+    }
+}
+
+/// The configured views and the fallback view for clients that match none of them.
+pub struct Views {
+    views: Vec<View>,
+    /// Index into `views`; if `None`, `default_view` is the fallback.
+    fallback: Option<usize>,
+    default_view: View,
+}
+
+impl Default for Views {
+    fn default() -> Self {
+        Self {
+            views: vec![],
+            fallback: None,
+            default_view: View::default_view(),
+        }
+    }
+}
+
+impl Views {
+    /// Loads the views from a JSON file of the following form.
+    ///
+    /// ```json
+    /// {
+    ///     "views": [
+    ///         { "name": "internal", "networks": ["10.0.0.0/8"], "db": 2, "dnssec_db": 3 },
+    ///         { "name": "partners", "tsig_keys": ["partners."], "key_prefix": "partners" }
+    ///     ],
+    ///     "fallback": "partners"
+    /// }
+    /// ```
+    ///
+    /// A client is in the first view that contains its address or the name of the TSIG key its
+    /// request was signed with. If `fallback` is omitted, clients that are in no view use the
+    /// default dbs without a key prefix.
+    pub fn from_file(path: impl AsRef<Path>, config: &Config) -> PektinResult<Self> {
+        let views_config: ViewsConfig = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let invalid = |msg: String| PektinError::InvalidConfig(msg);
+
+        let views = views_config
+            .views
+            .into_iter()
+            .map(|view| {
+                let clients = ClientMatcher::parse(&view.networks, &view.tsig_keys, "view")?;
+                let db_pools = match (view.db, view.dnssec_db) {
+                    (Some(db), Some(dnssec_db)) => Some((
+                        config.create_db_pool(db)?,
+                        config.create_db_pool(dnssec_db)?,
+                    )),
+                    (None, None) => None,
+                    _ => {
+                        return Err(invalid(format!(
+                            "view {} must set both db and dnssec_db or neither",
+                            view.name
+                        )))
+                    }
+                };
+                Ok(View {
+                    name: view.name,
+                    clients,
+                    key_prefix: view.key_prefix,
+                    db_pools,
+                })
+            })
+            .collect::<PektinResult<Vec<_>>>()?;
+
+        let fallback = match views_config.fallback {
+            Some(name) => Some(
+                views
+                    .iter()
+                    .position(|view| view.name == name)
+                    .ok_or_else(|| invalid(format!("fallback view {} doesn't exist", name)))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            views,
+            fallback,
+            default_view: View::default_view(),
+        })
+    }
+
+    /// Returns the view the client is in, given its address and the name of the (verified) TSIG
+    /// key its request was signed with.
+    pub fn select(&self, client_ip: Option<IpAddr>, tsig_key: Option<&Name>) -> &View {
+        self.views
+            .iter()
+            .find(|view| view.clients.matches(client_ip, tsig_key))
+            .or_else(|| self.fallback.map(|index| &self.views[index]))
+            .unwrap_or(&self.default_view)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(name: &str, networks: &[&str], tsig_keys: &[&str]) -> View {
+        let networks: Vec<String> = networks.iter().map(|net| net.to_string()).collect();
+        let tsig_keys: Vec<String> = tsig_keys.iter().map(|key| key.to_string()).collect();
+        View {
+            name: name.into(),
+            clients: ClientMatcher::parse(&networks, &tsig_keys, "view").unwrap(),
+            key_prefix: Some(name.into()),
+            db_pools: None,
+        }
+    }
+
+    fn views(fallback: Option<usize>) -> Views {
+        Views {
+            views: vec![
+                view("office", &["10.1.0.0/16"], &[]),
+                view("internal", &["10.0.0.0/8", "fd00::/8"], &["internal."]),
+                view("partners", &[], &["partners."]),
+            ],
+            fallback,
+            default_view: View::default_view(),
+        }
+    }
+
+    fn select<'a>(views: &'a Views, client_ip: Option<&str>, tsig_key: Option<&str>) -> &'a str {
+        let client_ip = client_ip.map(|ip| ip.parse().unwrap());
+        let tsig_key = tsig_key.map(|key| Name::from_ascii(key).unwrap());
+        &views.select(client_ip, tsig_key.as_ref()).name
+    }
+
+    #[test]
+    fn selects_the_first_matching_view() {
+        let views = views(None);
+        assert_eq!(select(&views, Some("10.1.2.3"), None), "office");
+        assert_eq!(select(&views, Some("10.2.3.4"), None), "internal");
+        assert_eq!(select(&views, Some("fd00::1"), None), "internal");
+        // IPv4-mapped addresses are in the IPv4 networks
+        assert_eq!(select(&views, Some("::ffff:10.2.3.4"), None), "internal");
+        // the address matches an earlier view than the key
+        assert_eq!(
+            select(&views, Some("10.1.2.3"), Some("partners.")),
+            "office"
+        );
+        assert_eq!(
+            select(&views, Some("192.0.2.1"), Some("Partners.")),
+            "partners"
+        );
+        assert_eq!(select(&views, None, Some("internal.")), "internal");
+    }
+
+    #[test]
+    fn falls_back_for_clients_in_no_view() {
+        let views_without_fallback = views(None);
+        let view = views_without_fallback.select(Some("192.0.2.1".parse().unwrap()), None);
+        assert_eq!(view.name, "default");
+        assert_eq!(view.key_prefix, None);
+        assert_eq!(
+            select(&views_without_fallback, None, Some("external.")),
+            "default"
+        );
+        assert_eq!(select(&views_without_fallback, None, None), "default");
+
+        let views_with_fallback = views(Some(2));
+        assert_eq!(
+            select(&views_with_fallback, Some("192.0.2.1"), None),
+            "partners"
+        );
+        assert_eq!(select(&views_with_fallback, None, None), "partners");
+        assert_eq!(
+            select(&views_with_fallback, Some("10.1.2.3"), None),
+            "office"
+        );
+
+        assert_eq!(select(&Views::default(), Some("10.1.2.3"), None), "default");
+    }
+}